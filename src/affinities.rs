@@ -0,0 +1,250 @@
+//! Pairwise affinities between particles, either stored or recomputed when needed
+
+use ndarray::{Array2, ArrayView1, ArrayViewMut1};
+
+use crate::algorithm::{particle_affinity, Real};
+use crate::features::Features;
+use crate::flags::{AffinityTransform, Csvdimreduce, Precision};
+use crate::Arr2;
+
+/// Number of distances sampled to find ranks for `--affinity-transform rank`
+const RANK_SAMPLE: usize = 100_000;
+
+pub enum Affinities {
+    /// Precomputed `n`×`n` matrix
+    Dense(Arr2),
+    DenseF32(Array2<f32>),
+    /// 16-bit fixed point values, to be multiplied by `scale`
+    Quantized {
+        values: Array2<u16>,
+        scale: f64,
+    },
+    /// Only per-particle features are kept. Each row of affinities is computed when it is needed,
+    /// trading extra work per pair for not having quadratic memory usage.
+    OnTheFly {
+        features: Features,
+        same_particle_force: f64,
+        transform: DistanceTransform,
+    },
+}
+
+/// `--affinity-transform` of distances between particles
+#[derive(Clone)]
+pub struct DistanceTransform {
+    kind: AffinityTransform,
+    /// Sorted distances between random pairs of different particles, or between all of them if there are few
+    sample: Vec<f64>,
+}
+
+impl Affinities {
+    /// Matrix of `f(j, k)` values stored as requested by `--precision` and `--quantize-affinities`,
+    /// without a temporary `f64` copy
+    pub fn build(opts: &Csvdimreduce, n: usize, f: impl Fn(usize, usize) -> f64) -> Affinities {
+        if opts.quantize_affinities {
+            let mut max = 0.0f64;
+            for j in 0..n {
+                for k in 0..n {
+                    max = max.max(f(j, k));
+                }
+            }
+            let scale = quantization_scale(max);
+            return Affinities::Quantized {
+                values: Array2::from_shape_fn((n, n), |(j, k)| quantize(f(j, k), scale)),
+                scale,
+            };
+        }
+        match opts.precision.unwrap_or_default() {
+            Precision::F64 => Affinities::Dense(Arr2::from_shape_fn((n, n), |(j, k)| f(j, k))),
+            Precision::F32 => {
+                Affinities::DenseF32(Array2::from_shape_fn((n, n), |(j, k)| f(j, k) as f32))
+            }
+        }
+    }
+
+    /// Convert the matrix to the storage requested by `--precision` and `--quantize-affinities`
+    pub fn dense(opts: &Csvdimreduce, m: Arr2) -> Affinities {
+        if opts.quantize_affinities {
+            let scale = quantization_scale(m.iter().copied().fold(0.0, f64::max));
+            return Affinities::Quantized {
+                values: m.mapv(|x| quantize(x, scale)),
+                scale,
+            };
+        }
+        match opts.precision.unwrap_or_default() {
+            Precision::F64 => Affinities::Dense(m),
+            Precision::F32 => Affinities::DenseF32(m.mapv(|x| x as f32)),
+        }
+    }
+
+    /// Affinities between the given particles only, stored like `self`
+    pub fn select(&self, opts: &Csvdimreduce, rows: &[usize]) -> Affinities {
+        match self {
+            Affinities::OnTheFly {
+                features,
+                same_particle_force,
+                transform,
+            } => Affinities::OnTheFly {
+                features: features.select(rows),
+                same_particle_force: *same_particle_force,
+                transform: transform.clone(),
+            },
+            _ => Affinities::build(opts, rows.len(), |j, k| self.get(rows[j], rows[k])),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            Affinities::Dense(m) => m.nrows(),
+            Affinities::DenseF32(m) => m.nrows(),
+            Affinities::Quantized { values, .. } => values.nrows(),
+            Affinities::OnTheFly { features, .. } => features.len(),
+        }
+    }
+
+    pub fn get(&self, j: usize, k: usize) -> f64 {
+        match self {
+            Affinities::Dense(m) => m[(j, k)],
+            Affinities::DenseF32(m) => m[(j, k)] as f64,
+            Affinities::Quantized { values, scale } => values[(j, k)] as f64 * scale,
+            Affinities::OnTheFly {
+                features,
+                same_particle_force,
+                transform,
+            } => particle_affinity(features, j, k, *same_particle_force, transform),
+        }
+    }
+
+    /// Affinities of particle `j` to all particles.
+    /// `buf` is used as storage if they are not precomputed in the requested type.
+    pub fn row<'s, T: Real>(
+        &'s self,
+        j: usize,
+        buf: &'s mut ArrayViewMut1<'_, T>,
+    ) -> ArrayView1<'s, T> {
+        match self {
+            Affinities::Dense(m) => match T::borrow_f64(m.row(j)) {
+                Some(row) => row,
+                None => fill(buf, m.row(j).iter().copied()),
+            },
+            Affinities::DenseF32(m) => match T::borrow_f32(m.row(j)) {
+                Some(row) => row,
+                None => fill(buf, m.row(j).iter().map(|&x| x as f64)),
+            },
+            Affinities::Quantized { values, scale } => {
+                fill(buf, values.row(j).iter().map(|&x| x as f64 * scale))
+            }
+            Affinities::OnTheFly {
+                features,
+                same_particle_force,
+                transform,
+            } => {
+                // Features of `j` stay in cache while others are scanned sequentially
+                fill(
+                    buf,
+                    (0..features.len()).map(|k| {
+                        particle_affinity(features, j, k, *same_particle_force, transform)
+                    }),
+                )
+            }
+        }
+    }
+
+    pub fn average(&self) -> f64 {
+        let n = self.len() as f64;
+        match self {
+            Affinities::Dense(m) => crate::algorithm::average_affinity(m.view()),
+            Affinities::DenseF32(m) => m.iter().map(|&x| x as f64).sum::<f64>() / (n * n),
+            Affinities::Quantized { values, scale } => {
+                values.iter().map(|&x| x as f64).sum::<f64>() * scale / (n * n)
+            }
+            Affinities::OnTheFly {
+                features,
+                same_particle_force,
+                transform,
+            } => {
+                let mut sum = 0.0;
+                for j in 0..features.len() {
+                    for k in j + 1..features.len() {
+                        sum += transform.apply(features.distance(j, k));
+                    }
+                }
+                // Each pair is counted twice in the matrix
+                same_particle_force + 2.0 * sum / (n * n)
+            }
+        }
+    }
+}
+
+impl DistanceTransform {
+    /// Transform requested by `--affinity-transform` for `n` particles with the given distances between them
+    pub fn new(
+        opts: &Csvdimreduce,
+        n: usize,
+        distance: impl Fn(usize, usize) -> f64,
+    ) -> DistanceTransform {
+        let kind = opts.affinity_transform.unwrap_or_default();
+        let mut sample = vec![];
+        if kind == AffinityTransform::Rank && n > 1 {
+            if n * (n - 1) <= RANK_SAMPLE {
+                for j in 0..n {
+                    sample.extend((0..n).filter(|&k| k != j).map(|k| distance(j, k)));
+                }
+            } else {
+                let mut rng = crate::rng::seeded(opts);
+                for _ in 0..RANK_SAMPLE {
+                    let j = crate::rng::index(&mut rng, 0..n);
+                    let k = (j + crate::rng::index(&mut rng, 1..n)) % n;
+                    sample.push(distance(j, k));
+                }
+            }
+            sample.sort_unstable_by(f64::total_cmp);
+        }
+        DistanceTransform { kind, sample }
+    }
+
+    pub fn apply(&self, d: f64) -> f64 {
+        match self.kind {
+            AffinityTransform::Raw => d,
+            AffinityTransform::Squared => d * d,
+            AffinityTransform::Log => libm::log1p(d),
+            AffinityTransform::Rank => self.rank(d),
+        }
+    }
+
+    /// Fraction of sampled distances below `d`, interpolated between neighbouring samples
+    fn rank(&self, d: f64) -> f64 {
+        let s = &self.sample;
+        let i = s.partition_point(|&x| x < d);
+        if i == 0 || s.len() < 2 {
+            return 0.0;
+        }
+        if i == s.len() {
+            return 1.0;
+        }
+        let (lo, hi) = (s[i - 1], s[i]);
+        let t = if hi > lo { (d - lo) / (hi - lo) } else { 1.0 };
+        (i as f64 - 1.0 + t) / (s.len() - 1) as f64
+    }
+}
+
+fn fill<'s, T: Real>(
+    buf: &'s mut ArrayViewMut1<'_, T>,
+    values: impl Iterator<Item = f64>,
+) -> ArrayView1<'s, T> {
+    for (x, v) in buf.iter_mut().zip(values) {
+        *x = T::of(v);
+    }
+    buf.view()
+}
+
+fn quantization_scale(max: f64) -> f64 {
+    if max > 0.0 {
+        max / u16::MAX as f64
+    } else {
+        1.0
+    }
+}
+
+fn quantize(x: f64, scale: f64) -> u16 {
+    (x / scale).round() as u16
+}
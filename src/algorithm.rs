@@ -4,22 +4,59 @@ use std::ops::Range;
 
 use ndarray::{Axis, s, azip};
 
+use crate::affinities::{Affinities, DistanceTransform};
+use crate::features::Features;
+use crate::flags::ForceLaw;
+use crate::sampler::Sampler;
+use crate::springs::Springs;
+
 pub type Ar2Mut<'a> = ndarray::ArrayViewMut2<'a, f64>;
 pub type Ar2Ref<'a> = ndarray::ArrayView2<'a, f64>;
 pub type Ar1Mut<'a> = ndarray::ArrayViewMut1<'a, f64>;
 pub type Ar1Ref<'a> = ndarray::ArrayView1<'a, f64>;
 
-pub struct State<'a> {
+/// Floating point type the simulation state is stored in
+pub trait Real: num_traits::Float + ndarray::LinalgScalar + ndarray::ScalarOperand + std::ops::AddAssign + std::ops::SubAssign + std::ops::MulAssign + std::ops::DivAssign + std::fmt::Display + Into<f64> {
+    fn of(x: f64) -> Self;
+    /// Use stored affinities without conversion if they are already of this type
+    fn borrow_f64<'a>(x: ndarray::ArrayView1<'a, f64>) -> Option<ndarray::ArrayView1<'a, Self>>;
+    fn borrow_f32<'a>(x: ndarray::ArrayView1<'a, f32>) -> Option<ndarray::ArrayView1<'a, Self>>;
+}
+
+impl Real for f64 {
+    fn of(x: f64) -> Self { x }
+    fn borrow_f64<'a>(x: ndarray::ArrayView1<'a, f64>) -> Option<ndarray::ArrayView1<'a, f64>> { Some(x) }
+    fn borrow_f32<'a>(_: ndarray::ArrayView1<'a, f32>) -> Option<ndarray::ArrayView1<'a, f64>> { None }
+}
+
+impl Real for f32 {
+    fn of(x: f64) -> Self { x as f32 }
+    fn borrow_f64<'a>(_: ndarray::ArrayView1<'a, f64>) -> Option<ndarray::ArrayView1<'a, f32>> { None }
+    fn borrow_f32<'a>(x: ndarray::ArrayView1<'a, f32>) -> Option<ndarray::ArrayView1<'a, f32>> { Some(x) }
+}
+
+/// Squared distance below which repulsion stops growing
+pub const MIN_SQNORM: f64 = 0.00001;
+/// Default `--same-particle-force`
+pub const DEFAULT_SAME_PARTICLE_FORCE: f64 = 0.2;
+
+pub struct State<'a, T: Real> {
     /// First dimension - particle index, Second dimension - coordinate
-    pub coords: Ar2Mut<'a>,
+    pub coords: ndarray::ArrayViewMut2<'a, T>,
     /// First dimension - particle index, Second dimension - coordinate
-    pub forces: Ar2Mut<'a>,
+    pub forces: ndarray::ArrayViewMut2<'a, T>,
     /// Dimensions the same as above.
-    pub inertias: Ar2Mut<'a>,
-    pub weights: Ar1Ref<'a>,
-    pub affinities: Ar2Ref<'a>,
+    pub inertias: ndarray::ArrayViewMut2<'a, T>,
+    pub weights: ndarray::ArrayView1<'a, T>,
+    pub affinities: &'a Affinities,
     /// Dimension is like in coordinates
-    pub tmp: Ar1Mut<'a>,
+    pub tmp: ndarray::ArrayViewMut1<'a, T>,
+    /// Storage for affinities that are not precomputed. Dimension is particle index.
+    pub affinity_row: ndarray::ArrayViewMut1<'a, T>,
+    /// Estimate repulsion from a sample of particles instead of all of them
+    pub sampler: Option<Sampler>,
+    /// Attraction to nearest neighbours in input space
+    pub springs: Option<Springs>,
     pub movement_scaler : f64,
 }
 pub struct Params {
@@ -31,81 +68,100 @@ pub struct Params {
     /// Like `squeeze_force`, but for coordinates above `squeeze_from`.
     pub squeeze_force2: f64,
     pub inertia_multiplier: f64,
+    pub force_law: ForceLaw,
     pub debug: bool,
 }
 
-impl<'a> State<'a> {
+impl<'a, T: Real> State<'a, T> {
     pub fn step(&mut self, params: &mut Params) {
         let n = self.coords.len_of(Axis(0));
         let cn = self.coords.len_of(Axis(1));
         assert_eq!(self.coords.dim(), self.forces.dim());
         assert_eq!(n, self.weights.len_of(Axis(0)));
         assert_eq!(cn, self.coords.len_of(Axis(1)));
-        assert_eq!(self.affinities.dim(), (n,n));
+        assert_eq!(self.affinities.len(), n);
 
-        let central_force = params.central_force;
+        let central_force = T::of(params.central_force);
         let squeeze_from = params.squeeze_from;
-        let squeeze_force = params.squeeze_force;
-        let squeeze_force2 = params.squeeze_force2;
+        let squeeze_force = T::of(params.squeeze_force);
+        let squeeze_force2 = T::of(params.squeeze_force2);
+        let min_sqnorm = T::of(MIN_SQNORM);
+        let force_law = params.force_law;
+        let half = T::of(0.5);
         
-        self.forces.fill(0.0);
+        self.forces.fill(T::zero());
         let mut vector = &mut self.tmp;
 
         let coords = self.coords.view();
         let mut forces = self.forces.view_mut();
-        let affinities = self.affinities.view();
+        let affinities = self.affinities;
+        let affinity_row = &mut self.affinity_row;
         let weights = self.weights.view();
+        let sampler = self.sampler.as_mut().filter(|x| !x.covers_all(n));
+        let sampled = sampler.is_some();
+        if let Some(sampler) = sampler {
+            for j in 0..n {
+                sampler.add_repulsion(j, coords, weights, affinities, force_law, forces.slice_mut(s![j, ..]));
+            }
+            // Only the estimate is clipped, other forces are exact
+            sampler.clip_outliers(forces.view_mut());
+        }
         for j in 0..n {
             let my_coords = coords.slice(s![j, ..]);
             let my_weight = weights[j];
-            let affinities_shard = affinities.slice(s![j, ..]);
             let mut my_forces = forces.slice_mut(s![j, ..]);
-            azip!((
-                index (p),
-                their_coords in coords.rows(),
-                affinity in affinities_shard,
-                their_weight in weights,
-            ) {
-                if j != p {
-                    vector.fill(0.0);
-                    let mut sqnorm = 0.0;
-                    azip!((vc in vector.view_mut(), myc in my_coords, theirc in their_coords) {
-                        *vc = myc - theirc;
-                        sqnorm += *vc * *vc;
-                    });
-
-                    if sqnorm < 0.00001 {
-                        sqnorm = 0.00001;
-                    }
-                    let norm = sqnorm.sqrt();
-                    for x in vector.iter_mut() {
-                        *x /= norm;
+            if !sampled {
+                let affinities_shard = affinities.row(j, affinity_row);
+                azip!((
+                    index (p),
+                    their_coords in coords.rows(),
+                    affinity in affinities_shard,
+                    their_weight in weights,
+                ) {
+                    if j != p {
+                        vector.fill(T::zero());
+                        let mut sqnorm = T::zero();
+                        azip!((vc in vector.view_mut(), myc in my_coords, theirc in their_coords) {
+                            *vc = *myc - *theirc;
+                            sqnorm += *vc * *vc;
+                        });
+
+                        if sqnorm < min_sqnorm {
+                            sqnorm = min_sqnorm;
+                        }
+                        let norm = sqnorm.sqrt();
+                        for x in vector.iter_mut() {
+                            *x /= norm;
+                        }
+                        let repelling_force = force_law.repulsion(*affinity, sqnorm) * *their_weight/my_weight;
+                        my_forces.scaled_add(repelling_force, vector);
                     }
-                    let repelling_force = affinity/sqnorm*their_weight/my_weight;
-                    my_forces.scaled_add(repelling_force, vector);
-                }
-            });
+                });
+            }
+            if let Some(ref springs) = self.springs {
+                springs.add_attraction(j, coords, weights, my_forces.view_mut());
+            }
             azip!((
                 index (c),
                 cc in my_coords,
                 ff in &mut my_forces,
             ) {
                 let mut cc = *cc;
-                cc = cc - 0.5;
+                cc -= half;
                 if c == squeeze_from {
-                    cc = squeeze_force*cc;
+                    cc *= squeeze_force;
                 } else if c > squeeze_from {
-                    cc = squeeze_force2*cc;
+                    cc *= squeeze_force2;
                 } else {
-                    cc = central_force*cc;
+                    cc *= central_force;
 
                 }
-                *ff -= (n as f64) * cc;
+                *ff -= T::of(n as f64) * cc;
             });
         }
         let mut maxforcecoord = 0.0;
         for f in forces {
-            maxforcecoord = f.abs().max(maxforcecoord);
+            maxforcecoord = (*f).into().abs().max(maxforcecoord);
         }
         if params.debug { println!("movement {maxforcecoord}"); }
         maxforcecoord = maxforcecoord.max(0.0001);
@@ -113,46 +169,68 @@ impl<'a> State<'a> {
         self.movement_scaler = self.movement_scaler * 0.8 + maxforcecoord * 0.2;
 
         /// Force some coordinate change to be `rate` regardless of forces scale
-        let scale = params.rate / self.movement_scaler;
+        let scale = T::of(params.rate / self.movement_scaler);
 
         self.inertias.scaled_add(scale, &self.forces);
-        self.coords.scaled_add(1.0, &self.inertias);
-        self.inertias.map_inplace(|x|*x *= params.inertia_multiplier);
+        self.coords.scaled_add(T::one(), &self.inertias);
+        let inertia_multiplier = T::of(params.inertia_multiplier);
+        self.inertias.map_inplace(|x|*x *= inertia_multiplier);
 
         for cc in self.coords.iter_mut() {
-            *cc = cc.clamp(0.0, 1.0);
+            *cc = num_traits::clamp(*cc, T::zero(), T::one());
         }
     }
 }
 
-pub fn build_particle_affinities<'a,'b>(input: Ar2Ref<'a>, mut output:Ar2Mut<'b>, same_particle_force: f64) {
-    let n = input.len_of(Axis(0));
-    let cn = input.len_of(Axis(1));
-    assert_eq!(output.dim(), (n,n));
-    output.fill(same_particle_force);
-    for j in 0..n {
-        for k in 0..n {
-            if n == k {
-                continue;
-            }
-            for c in 0..cn {
-                output[(j,k)] += (input[(j,c)] - input[(k,c)]).abs();
-            }
+impl ForceLaw {
+    /// Repulsion between particles with the given affinity at squared distance `sqnorm`.
+    /// Transcendental functions come from `libm`, as platform ones may differ in the last bits.
+    pub fn repulsion<T: Real>(self, affinity: T, sqnorm: T) -> T {
+        let sq: f64 = sqnorm.into();
+        match self {
+            ForceLaw::InverseSquare => affinity / sqnorm,
+            ForceLaw::Power(p) => affinity / T::of(libm::pow(sq, 0.5 * p)),
+            ForceLaw::Exponential(s) => affinity * T::of(libm::exp(-sq.sqrt() / s)),
+            ForceLaw::Gaussian(s) => affinity * T::of(libm::exp(-sq / (2.0 * s * s))),
+            ForceLaw::Softened(eps) => affinity / (sqnorm + T::of(eps * eps)),
         }
     }
 }
 
+impl<'a, T: Real> State<'a, T> {
+    /// Find a particle with `NaN` or infinite coordinate
+    pub fn find_non_finite(&self) -> Option<(usize, usize)> {
+        self.coords.indexed_iter().find(|(_, x)| !x.is_finite()).map(|(ix, _)| ix)
+    }
+}
+
+pub fn particle_affinity(input: &Features, j: usize, k: usize, same_particle_force: f64, transform: &DistanceTransform) -> f64 {
+    if j == k {
+        same_particle_force
+    } else {
+        same_particle_force + transform.apply(input.distance(j, k))
+    }
+}
+
 pub fn average_affinity<'a>(matrix: Ar2Ref<'a>) -> f64 {
     matrix.sum() / matrix.len() as f64
 }
 
-pub fn normalize<'a>(mut inputvals: Ar2Mut<'a>) {
+/// Returns the average subtracted from each column and the scale it was divided by
+pub fn normalize<'a>(mut inputvals: Ar2Mut<'a>) -> Vec<(f64, f64)> {
     let n_input_coords = inputvals.len_of(Axis(1));
+    let mut scales = Vec::with_capacity(n_input_coords);
     for j in 0..n_input_coords {
         let mut s = inputvals.slice_mut(s![.., j]);
         let avg = s.sum() / s.len() as f64;
         s -= avg;
-        let scale : f64 = s.dot(&s).sqrt();
+        let mut scale : f64 = s.dot(&s).sqrt();
+        if scale == 0.0 {
+            // Constant column
+            scale = 1.0;
+        }
         s /= scale;
+        scales.push((avg, scale));
     }
+    scales
 }
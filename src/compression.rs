@@ -0,0 +1,102 @@
+//! Transparent gzip and zstd support for input and output files
+
+use std::io::{Read, Write};
+use std::path::Path;
+
+/// Decompress the stream if it starts with gzip or zstd magic bytes
+pub fn decompress(f: Box<dyn Read>) -> std::io::Result<Box<dyn Read>> {
+    let (head, f) = crate::dialect::peek(f, 4)?;
+    Ok(if head.starts_with(&[0x1f, 0x8b]) {
+        Box::new(flate2::read::MultiGzDecoder::new(f))
+    } else if head == [0x28, 0xb5, 0x2f, 0xfd] {
+        Box::new(zstd::Decoder::new(f)?)
+    } else {
+        f
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    pub fn from_path(path: &Path) -> Compression {
+        match path.extension().and_then(|x| x.to_str()) {
+            Some("gz") => Compression::Gzip,
+            Some("zst" | "zstd") => Compression::Zstd,
+            _ => Compression::None,
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Compression::None => "",
+            Compression::Gzip => ".gz",
+            Compression::Zstd => ".zst",
+        }
+    }
+}
+
+/// Path without compression extension, for deciding file format
+pub fn strip_extension(path: &Path) -> &Path {
+    if Compression::from_path(path) == Compression::None {
+        path
+    } else {
+        path.file_stem().map_or(path, Path::new)
+    }
+}
+
+/// Output stream which must be `finish`ed to write compression trailers and report errors
+pub enum OutputStream {
+    Plain(Box<dyn Write>),
+    Gzip(flate2::write::GzEncoder<Box<dyn Write>>),
+    Zstd(zstd::Encoder<'static, Box<dyn Write>>),
+}
+
+impl OutputStream {
+    pub fn new(f: Box<dyn Write>, compression: Compression) -> std::io::Result<OutputStream> {
+        Ok(match compression {
+            Compression::None => OutputStream::Plain(f),
+            Compression::Gzip => OutputStream::Gzip(flate2::write::GzEncoder::new(
+                f,
+                flate2::Compression::default(),
+            )),
+            Compression::Zstd => OutputStream::Zstd(zstd::Encoder::new(f, 0)?),
+        })
+    }
+
+    pub fn finish(self) -> std::io::Result<()> {
+        match self {
+            OutputStream::Plain(mut f) => f.flush(),
+            OutputStream::Gzip(f) => f.finish()?.flush(),
+            OutputStream::Zstd(f) => f.finish()?.flush(),
+        }
+    }
+}
+
+impl Write for OutputStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            OutputStream::Plain(f) => f.write(buf),
+            OutputStream::Gzip(f) => f.write(buf),
+            OutputStream::Zstd(f) => f.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            OutputStream::Plain(f) => f.flush(),
+            OutputStream::Gzip(f) => f.flush(),
+            OutputStream::Zstd(f) => f.flush(),
+        }
+    }
+}
+
+/// Flush CSV writer and finish the underlying stream
+pub fn finish_csv(f: csv::Writer<OutputStream>) -> anyhow::Result<()> {
+    f.into_inner().map_err(|e| e.into_error())?.finish()?;
+    Ok(())
+}
@@ -0,0 +1,469 @@
+//! `--config` files and `--preset`s: options read from TOML, overridden by the command line
+
+use std::ffi::OsString;
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use toml::{Table, Value};
+
+use crate::flags::{Csvdimreduce, InputMode, LandmarkSelection, OnError, ValuePolicy};
+
+/// Option names and values like in `--config` files
+const PRESETS: &[(&str, &str)] = &[
+    (
+        "fast",
+        r#"
+        n-iters = 40
+        rate = 0.02
+        multilevel = true
+        "#,
+    ),
+    (
+        "quality",
+        r#"
+        n-iters = 300
+        neighbors = 10
+        "#,
+    ),
+    (
+        "2d-map",
+        r#"
+        retain_coords_from_squeezing = 2
+        n-iters = 200
+        central-force = 10
+        squeeze-final-force = 200
+        "#,
+    ),
+];
+
+/// Command line options merged with `--replay`ed run, `--config` file and `--preset`, in this order of precedence.
+/// Exits on usage errors like xflags does.
+pub fn from_env_or_exit() -> anyhow::Result<Csvdimreduce> {
+    let mut args: Vec<OsString> = std::env::args_os().skip(1).collect();
+    // Positional arguments are needed to parse the command line at all
+    let replay = args
+        .iter()
+        .position(|x| x == "--replay")
+        .and_then(|i| args.get(i + 1))
+        .map(PathBuf::from);
+    let mut recorded = None;
+    if let Some(ref path) = replay {
+        let (positional, parameters) = crate::metadata::load_replay(path)?;
+        args.splice(0..0, positional);
+        recorded = Some(parameters);
+    }
+    let mut opts = Csvdimreduce::from_vec(args.clone()).unwrap_or_else(|e| e.exit());
+    if let Some(table) = recorded {
+        merge(&mut args, &table, "replayed run")?;
+        opts = Csvdimreduce::from_vec(args.clone())?;
+    }
+    if let Some(ref path) = opts.config {
+        let table = load(path)?;
+        merge(&mut args, &table, &path.display().to_string())?;
+        opts = Csvdimreduce::from_vec(args.clone())?;
+    }
+    // Preset may also come from the config file
+    if let Some(ref name) = opts.preset {
+        let Some((_, text)) = PRESETS.iter().find(|(n, _)| n == name) else {
+            let names: Vec<&str> = PRESETS.iter().map(|(n, _)| *n).collect();
+            anyhow::bail!(
+                "Unknown preset `{name}`, expected one of {}",
+                names.join(", ")
+            );
+        };
+        let table: Table = toml::from_str(text)?;
+        merge(&mut args, &table, &format!("preset {name}"))?;
+        opts = Csvdimreduce::from_vec(args)?;
+    }
+    Ok(opts)
+}
+
+fn load(path: &Path) -> anyhow::Result<Table> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read config {}", path.display()))?;
+    toml::from_str(&text).with_context(|| format!("Failed to parse config {}", path.display()))
+}
+
+/// Add options from `table` to `args`, except ones already there.
+/// They are inserted in front, so that for repeated options like `--column-type`,
+/// where the last one wins, `args` still take precedence.
+fn merge(args: &mut Vec<OsString>, table: &Table, source: &str) -> anyhow::Result<()> {
+    for (key, value) in table {
+        // Names in files may use `_` and `-` interchangeably
+        let Some(option) = [key.clone(), key.replace('_', "-"), key.replace('-', "_")]
+            .iter()
+            .find_map(|name| declared(name))
+        else {
+            anyhow::bail!("{key} in {source}: unknown option");
+        };
+        // Specified on the command line or in a source with higher precedence
+        if !option.repeated && args.iter().any(|x| option.matches(x)) {
+            continue;
+        }
+        let tokens = tokens(option.name, value).with_context(|| format!("{key} in {source}"))?;
+        args.splice(0..0, tokens);
+        Csvdimreduce::from_vec(args.clone()).with_context(|| format!("{key} in {source}"))?;
+    }
+    Ok(())
+}
+
+/// Option as declared in the `xflags!` block of `flags.rs`
+struct Declared {
+    /// Long name without leading `--`
+    name: &'static str,
+    short: Option<&'static str>,
+    repeated: bool,
+}
+
+impl Declared {
+    fn matches(&self, arg: &OsString) -> bool {
+        arg.to_str()
+            .is_some_and(|x| x.strip_prefix("--") == Some(self.name) || self.short == Some(x))
+    }
+}
+
+/// Declaration of `--name` option, read from lines like `optional -n, --n-iters n: usize`
+fn declared(name: &str) -> Option<Declared> {
+    include_str!("flags.rs").lines().find_map(|line| {
+        let line = line.trim();
+        let (repeated, rest) = if let Some(rest) = line.strip_prefix("optional ") {
+            (false, rest)
+        } else {
+            (true, line.strip_prefix("repeated ")?)
+        };
+        let mut words = rest.split([',', ' ']).filter(|x| !x.is_empty());
+        let mut first = words.next()?;
+        let mut short = None;
+        if !first.starts_with("--") && first.starts_with('-') {
+            short = Some(first);
+            first = words.next()?;
+        }
+        (first.strip_prefix("--")? == name).then_some(Declared {
+            name: &first[2..],
+            short,
+            repeated,
+        })
+    })
+}
+
+/// Command line arguments for one option
+fn tokens(name: &str, value: &Value) -> anyhow::Result<Vec<OsString>> {
+    let flag = OsString::from(format!("--{name}"));
+    let single = |v: &Value| -> anyhow::Result<OsString> {
+        Ok(match v {
+            Value::String(s) => s.into(),
+            Value::Integer(x) => x.to_string().into(),
+            Value::Float(x) => x.to_string().into(),
+            _ => anyhow::bail!("Value should be a string, a number, a boolean or an array of them"),
+        })
+    };
+    Ok(match value {
+        Value::Boolean(true) => vec![flag],
+        Value::Boolean(false) => vec![],
+        Value::Array(values) => {
+            let mut v = vec![];
+            for x in values {
+                v.push(flag.clone());
+                v.push(single(x)?);
+            }
+            v
+        }
+        x => vec![flag, single(x)?],
+    })
+}
+
+/// [`resolved`] options as a `--config` file
+pub fn dump(opts: &Csvdimreduce) -> anyhow::Result<String> {
+    Ok(toml::to_string(&resolved(opts))?)
+}
+
+/// All options except positional arguments, with defaults filled in
+pub fn resolved(opts: &Csvdimreduce) -> Table {
+    use crate::schedule::{DEFAULT_CENTRAL_FORCE, DEFAULT_INERTIA_MULTIPLIER, DEFAULT_RATE};
+
+    let mut t = Table::new();
+    let mut set = |key: &str, value: Option<Value>| {
+        if let Some(v) = value {
+            t.insert(key.to_owned(), v);
+        }
+    };
+
+    let iterations = opts.iterations();
+    let rate = opts.rate.unwrap_or(DEFAULT_RATE);
+    let central_force = opts.central_force.unwrap_or(DEFAULT_CENTRAL_FORCE);
+    let squeeze_rampup_rate = opts.squeeze_rampup_rate.unwrap_or(rate * 0.2);
+    // Defaults of table-only options are left out for other input modes, which reject them
+    let table = matches!(opts.input_mode.unwrap_or_default(), InputMode::Table);
+
+    set("save-each-n-iters", int(opts.save_each_n_iters));
+    set(
+        "trajectory",
+        text(opts.trajectory.as_ref().map(|p| p.display())),
+    );
+    set("no-header", switch(opts.no_header));
+    set("no-output-header", switch(opts.no_output_header));
+    set("delimiter", text(opts.delimiter.as_ref()));
+    set("record-delimiter", text(opts.record_delimiter.as_ref()));
+    set("quote", text(opts.quote.as_ref()));
+    set("escape", text(opts.escape.as_ref()));
+    set("comment", text(opts.comment.as_ref()));
+    set("flexible", switch(opts.flexible));
+    set("output-delimiter", text(opts.output_delimiter.as_ref()));
+    set(
+        "output-record-delimiter",
+        text(opts.output_record_delimiter.as_ref()),
+    );
+    set("output-quote", text(opts.output_quote.as_ref()));
+    set("output-escape", text(opts.output_escape.as_ref()));
+    set("output", text(opts.output.as_ref().map(|p| p.display())));
+    set(
+        "random-seed",
+        Some(Value::Integer(crate::rng::seed(opts) as i64)),
+    );
+    set("weight", int(opts.weight));
+    set("n-iters", int(Some(iterations.basic)));
+    set("rate", float(rate));
+    set(
+        "inertia-multiplier",
+        float(
+            opts.inertia_multiplier
+                .unwrap_or(DEFAULT_INERTIA_MULTIPLIER),
+        ),
+    );
+    set("final-rate", float(opts.final_rate.unwrap_or(0.02 * rate)));
+    set("central-force", float(central_force));
+    set("same-particle-force", float(opts.same_particle_force()));
+    set(
+        "retain_coords_from_squeezing",
+        int(opts.retain_coords_from_squeezing),
+    );
+    set("squeeze-rampup-rate", float(squeeze_rampup_rate));
+    set("squeeze-rampup-iters", int(Some(iterations.squeeze_rampup)));
+    set(
+        "squeeze-final-force",
+        float(opts.squeeze_final_force.unwrap_or(10.0 * central_force)),
+    );
+    set(
+        "squeeze-final-initial-rate",
+        float(
+            opts.squeeze_final_initial_rate
+                .unwrap_or(squeeze_rampup_rate),
+        ),
+    );
+    set("squeeze-final-iters", int(Some(iterations.squeeze_final)));
+    set("warmup-iterations", int(Some(iterations.warmup)));
+    set("debug", switch(opts.debug));
+    set("normalize", switch(opts.normalize));
+    set(
+        "column-type",
+        Some(Value::Array(
+            opts.column_type
+                .iter()
+                .map(|x| Value::String(x.to_string()))
+                .collect(),
+        )),
+    );
+    set(
+        "text-metric",
+        text(
+            opts.text_metric
+                .or(table.then_some(crate::text::TextMetric::NGrams(3)))
+                .as_ref(),
+        ),
+    );
+    set(
+        "text-weight",
+        opts.text_weight.or(table.then_some(1.0)).and_then(float),
+    );
+    set(
+        "input-mode",
+        text(Some(&opts.input_mode.unwrap_or_default())),
+    );
+    set("labels", text(opts.labels.as_ref().map(|p| p.display())));
+    set(
+        "disconnected-distance",
+        opts.disconnected_distance.and_then(float),
+    );
+    set(
+        "decimal-separator",
+        text(Some(&opts.decimal_separator.unwrap_or('.'))),
+    );
+    set(
+        "grouping-separators",
+        text(opts.grouping_separators.as_ref()),
+    );
+    set("strip-units", switch(opts.strip_units));
+    set(
+        "on-error",
+        text(
+            opts.on_error
+                .clone()
+                .or(table.then(OnError::default))
+                .as_ref(),
+        ),
+    );
+    set("keep-skipped", switch(opts.keep_skipped));
+    set(
+        "non-finite",
+        text(
+            opts.non_finite
+                .or(table.then(ValuePolicy::default))
+                .as_ref(),
+        ),
+    );
+    set(
+        "bad-weights",
+        text(
+            opts.bad_weights
+                .or(table.then(ValuePolicy::default))
+                .as_ref(),
+        ),
+    );
+    set("max-memory", text(opts.max_memory.as_ref()));
+    set(
+        "max-runtime",
+        text(Some(&opts.max_runtime.unwrap_or(crate::flags::TimeLimit(
+            crate::estimate::DEFAULT_MAX_RUNTIME,
+        )))),
+    );
+    set("force", switch(opts.force));
+    set(
+        "affinities",
+        text(Some(&opts.affinities.unwrap_or_default())),
+    );
+    set("precision", text(Some(&opts.precision.unwrap_or_default())));
+    set("quantize-affinities", switch(opts.quantize_affinities));
+    set("batch-size", int(opts.batch_size));
+    set("landmarks", int(opts.landmarks));
+    set(
+        "landmark-selection",
+        text(
+            opts.landmark_selection
+                .or(table.then(LandmarkSelection::default))
+                .as_ref(),
+        ),
+    );
+    set("multilevel", switch(opts.multilevel));
+    set(
+        "coarsest",
+        int(Some(
+            opts.coarsest.unwrap_or(crate::multilevel::DEFAULT_COARSEST),
+        )),
+    );
+    set(
+        "level-iters",
+        int(Some(
+            opts.level_iters
+                .unwrap_or(crate::multilevel::DEFAULT_LEVEL_ITERS),
+        )),
+    );
+    set("neighbors", int(opts.neighbors));
+    set(
+        "spring-constant",
+        float(
+            opts.spring_constant
+                .unwrap_or(crate::springs::DEFAULT_SPRING_CONSTANT),
+        ),
+    );
+    set(
+        "spring-length",
+        float(
+            opts.spring_length
+                .unwrap_or(crate::springs::DEFAULT_SPRING_LENGTH),
+        ),
+    );
+    set("force-law", text(Some(&opts.force_law.unwrap_or_default())));
+    set(
+        "affinity-transform",
+        text(Some(&opts.affinity_transform.unwrap_or_default())),
+    );
+    set(
+        "schedule",
+        text(opts.schedule.as_ref().map(|p| p.display())),
+    );
+    set("restarts", int(Some(opts.restarts.unwrap_or(1))));
+    set("all-restarts", switch(opts.all_restarts));
+    set("stability", int(opts.stability));
+    set("stability-neighbors", int(opts.stability_neighbors));
+    set("threads", int(opts.threads));
+    set("metadata", switch(opts.metadata));
+    t
+}
+
+fn text(x: Option<impl Display>) -> Option<Value> {
+    x.map(|x| Value::String(x.to_string()))
+}
+
+fn int(x: Option<usize>) -> Option<Value> {
+    x.map(|x| Value::Integer(x as i64))
+}
+
+fn float(x: f64) -> Option<Value> {
+    Some(Value::Float(x))
+}
+
+fn switch(x: bool) -> Option<Value> {
+    Some(Value::Boolean(x))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(x: &[&str]) -> Vec<OsString> {
+        x.iter().map(OsString::from).collect()
+    }
+
+    #[test]
+    fn declarations() {
+        let n = declared("n-iters").unwrap();
+        assert_eq!(
+            (n.name, n.short, n.repeated),
+            ("n-iters", Some("-n"), false)
+        );
+        let c = declared("column-type").unwrap();
+        assert_eq!((c.short, c.repeated), (None, true));
+        assert!(declared("retain_coords_from_squeezing").is_some());
+        assert!(declared("path").is_none());
+        assert!(declared("no-such-option").is_none());
+    }
+
+    #[test]
+    fn everything_resolved_is_declared() {
+        let opts = Csvdimreduce::from_vec(args(&["1:2", "2"])).unwrap();
+        for key in resolved(&opts).keys() {
+            assert!(declared(key).is_some(), "{key}");
+        }
+        for (name, text) in PRESETS {
+            for key in toml::from_str::<Table>(text).unwrap().keys() {
+                assert!(declared(key).is_some(), "{key} in preset {name}");
+            }
+        }
+    }
+
+    #[test]
+    fn command_line_wins() {
+        let table: Table =
+            toml::from_str("n_iters = 7\nrate = 0.5\ncolumn-type = ['2=date']\nnormalize = true")
+                .unwrap();
+        let mut a = args(&["1:2", "2", "-n", "5", "--column-type", "2=number"]);
+        merge(&mut a, &table, "test").unwrap();
+        let opts = Csvdimreduce::from_vec(a).unwrap();
+        assert_eq!(opts.n_iters, Some(5));
+        assert_eq!(opts.rate, Some(0.5));
+        assert!(opts.normalize);
+        assert_eq!(opts.column_kind(2), crate::flags::ColumnKind::Number);
+    }
+
+    #[test]
+    fn merge_errors() {
+        let mut a = args(&["1:2", "2"]);
+        let table: Table = toml::from_str("rate = 'fast'").unwrap();
+        let e = merge(&mut a, &table, "test").unwrap_err();
+        assert!(format!("{e:#}").starts_with("rate in test: "), "{e:#}");
+        let table: Table = toml::from_str("speed = 1").unwrap();
+        let e = merge(&mut a, &table, "test").unwrap_err();
+        assert_eq!(e.to_string(), "speed in test: unknown option");
+    }
+}
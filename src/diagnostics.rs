@@ -0,0 +1,62 @@
+//! Human-readable locations of input problems
+
+/// Like `data row 5 (line 6)`. `j` is 0-based index of the data row.
+pub fn record_location(j: usize, record: &csv::ByteRecord) -> String {
+    match record.position() {
+        Some(p) => format!("data row {} (line {})", j + 1, p.line()),
+        None => format!("data row {}", j + 1),
+    }
+}
+
+/// Like ``column 3 (`price`), value `abc`, data row 5 (line 6)``. `column` is 1-based.
+pub fn field_location(
+    header: Option<&csv::ByteRecord>,
+    j: usize,
+    record: &csv::ByteRecord,
+    column: usize,
+) -> String {
+    let name = header
+        .and_then(|h| h.get(column - 1))
+        .map(|x| format!(" (`{}`)", String::from_utf8_lossy(x)))
+        .unwrap_or_default();
+    let value = record
+        .get(column - 1)
+        .map(|x| format!(", value `{}`", String::from_utf8_lossy(x)))
+        .unwrap_or_default();
+    format!(
+        "column {column}{name}{value}, {}",
+        record_location(j, record)
+    )
+}
+
+/// Like `header (line 1)`
+pub fn header_location(header: &csv::ByteRecord) -> String {
+    match header.position() {
+        Some(p) => format!("header (line {})", p.line()),
+        None => "header".to_owned(),
+    }
+}
+
+/// Fail, listing all the 1-based column numbers that are beyond the record width.
+/// `location` describes the record, like [`record_location`].
+pub fn check_columns(
+    what: &str,
+    columns: impl IntoIterator<Item = usize>,
+    record: &csv::ByteRecord,
+    location: impl FnOnce() -> String,
+) -> anyhow::Result<()> {
+    let invalid: Vec<String> = columns
+        .into_iter()
+        .filter(|&i| i == 0 || i > record.len())
+        .map(|i| i.to_string())
+        .collect();
+    if !invalid.is_empty() {
+        anyhow::bail!(
+            "{what} references invalid column numbers {}: {} has only {} fields",
+            invalid.join(", "),
+            location(),
+            record.len()
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,135 @@
+//! Guessing properties of input CSV files
+
+use std::io::Read;
+
+/// Read up to `n` bytes from the beginning of the stream without losing them
+pub fn peek(mut f: Box<dyn Read>, n: usize) -> std::io::Result<(Vec<u8>, Box<dyn Read>)> {
+    let mut head = Vec::with_capacity(n);
+    (&mut f).take(n as u64).read_to_end(&mut head)?;
+    let rest = std::io::Cursor::new(head.clone()).chain(f);
+    Ok((head, Box::new(rest)))
+}
+
+/// Skip UTF-8 byte order mark, if present
+pub fn strip_bom(f: Box<dyn Read>) -> std::io::Result<Box<dyn Read>> {
+    let (head, mut f) = peek(f, 3)?;
+    if head == b"\xEF\xBB\xBF" {
+        std::io::copy(&mut (&mut f).take(3), &mut std::io::sink())?;
+    }
+    Ok(f)
+}
+
+const CANDIDATES: &[u8] = b",\t;| ";
+
+/// Choose the delimiter that appears the same non-zero number of times in each of the first lines.
+/// If there is no such candidate, choose the one with the largest minimal count. Default is comma.
+pub fn sniff_delimiter(head: &[u8], quote: u8, comment: Option<u8>, complete: bool) -> u8 {
+    let mut lines: Vec<&[u8]> = head
+        .split(|&c| c == b'\n')
+        .filter(|l| !l.is_empty() && Some(l[0]) != comment)
+        .collect();
+    if !complete && lines.len() > 1 {
+        // Probably truncated
+        lines.pop();
+    }
+    lines.truncate(20);
+    if lines.is_empty() {
+        return b',';
+    }
+
+    let mut best = (b',', false, 0usize);
+    for &d in CANDIDATES {
+        let counts: Vec<usize> = lines.iter().map(|l| count_unquoted(l, d, quote)).collect();
+        let min = *counts.iter().min().unwrap();
+        if min == 0 {
+            continue;
+        }
+        let consistent = counts.iter().all(|&x| x == min);
+        if (consistent, min) > (best.1, best.2) {
+            best = (d, consistent, min);
+        }
+    }
+    best.0
+}
+
+fn count_unquoted(line: &[u8], d: u8, quote: u8) -> usize {
+    let mut in_quotes = false;
+    let mut n = 0;
+    for &c in line {
+        if c == quote {
+            in_quotes = !in_quotes;
+        } else if c == d && !in_quotes {
+            n += 1;
+        }
+    }
+    n
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flags::{DelimiterSpecifier, FieldDelimiter};
+
+    fn sniff(head: &[u8]) -> u8 {
+        sniff_delimiter(head, b'"', None, true)
+    }
+
+    #[test]
+    fn sniffing() {
+        assert_eq!(sniff(b"a,b,c\n1,2,3\n4,5,6\n"), b',');
+        assert_eq!(sniff(b"a;b;c\n1,5;2,5;3\n4;5;6\n"), b';');
+        assert_eq!(sniff(b"a\tb\tc\n1 2\t2\t3\n"), b'\t');
+        assert_eq!(sniff(b"a|b\n1|2\n"), b'|');
+        // Quoted delimiters do not count
+        assert_eq!(sniff(b"name;x\n\"a;b;c\";1\n\"d\";2\n"), b';');
+        // Commented lines are ignored
+        assert_eq!(
+            sniff_delimiter(b"# a,b,c\nx;y\n1;2\n", b'"', Some(b'#'), true),
+            b';'
+        );
+        // Last line of an incomplete sample may be cut short
+        assert_eq!(
+            sniff_delimiter(b"a;b;c\n1;2;3\n4;5", b'"', None, false),
+            b';'
+        );
+        assert_eq!(sniff(b""), b',');
+        assert_eq!(sniff(b"single\ncolumn\n"), b',');
+    }
+
+    #[test]
+    fn escapes() {
+        let byte = |s: &str| s.parse::<DelimiterSpecifier>().map(|x| x.0).ok();
+        assert_eq!(byte("\\t"), Some(b'\t'));
+        assert_eq!(byte("\\x1f"), Some(0x1f));
+        assert_eq!(byte("\\x1F"), Some(0x1f));
+        assert_eq!(byte("\\0"), Some(0));
+        assert_eq!(byte("\\\\"), Some(b'\\'));
+        assert_eq!(byte("\\"), Some(b'\\'));
+        assert_eq!(byte(";"), Some(b';'));
+        for bad in ["", ",,", "\\x1", "\\xzz", "é"] {
+            assert_eq!(byte(bad), None, "{bad}");
+        }
+        for b in [b'\t', b'\\', 0x1f, b';', 0] {
+            let shown = DelimiterSpecifier(b).to_string();
+            assert_eq!(byte(&shown), Some(b), "{shown}");
+        }
+        assert!(matches!("auto".parse(), Ok(FieldDelimiter::Auto)));
+        assert!(matches!("\\t".parse(), Ok(FieldDelimiter::Byte(b'\t'))));
+    }
+
+    #[test]
+    fn byte_order_mark() {
+        let read = |data: &'static [u8]| {
+            let mut text = vec![];
+            strip_bom(Box::new(data))
+                .unwrap()
+                .read_to_end(&mut text)
+                .unwrap();
+            text
+        };
+        assert_eq!(read(b"\xEF\xBB\xBFa,b\n"), b"a,b\n");
+        assert_eq!(read(b"a,b\n"), b"a,b\n");
+        assert_eq!(read(b"\xEF\xBB"), b"\xEF\xBB");
+        assert_eq!(read(b""), b"");
+    }
+}
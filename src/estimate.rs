@@ -0,0 +1,224 @@
+//! Predicting memory usage and running time before allocating anything quadratic
+
+use crate::flags::{AffinityStorage, Csvdimreduce, InputMode};
+use crate::multilevel::equivalent_iterations;
+use crate::sampler::OVERSAMPLING;
+
+/// Cost of one iteration per pair of particles, measured with a release build on a typical x86-64 machine
+const NS_PER_PAIR: f64 = 25.0;
+/// Additional per-pair cost of each output coordinate
+const NS_PER_PAIR_PER_COORD: f64 = 4.0;
+/// Additional per-pair cost of computing affinities on the fly, plus the cost of each input column
+const NS_PER_PAIR_ON_THE_FLY: f64 = 10.0;
+const NS_PER_PAIR_PER_FEATURE: f64 = 0.5;
+/// Cost of each partner with `--batch-size`, including drawing candidates and computing their affinities
+const NS_PER_SAMPLED_PARTNER: f64 = 200.0;
+/// Used when available memory cannot be determined
+const DEFAULT_MAX_MEMORY: u64 = 8 << 30;
+pub const DEFAULT_MAX_RUNTIME: f64 = 3600.0;
+/// Print the estimate even without `--debug` when it is above these
+const NOTABLE_MEMORY: u64 = 1 << 30;
+const NOTABLE_RUNTIME: f64 = 10.0;
+
+pub struct Estimate {
+    pub memory: u64,
+    pub iteration_seconds: f64,
+    /// Counted as iterations over all particles, see `equivalent_iterations`
+    pub total_iters: usize,
+    pub on_the_fly: bool,
+}
+
+impl Estimate {
+    /// `pair_bytes` is the memory needed per pair of particles while reading input,
+    /// `features` is the number of `f64` values stored per particle besides the simulation state.
+    pub fn new(opts: &Csvdimreduce, n: usize, pair_bytes: usize, features: usize) -> Estimate {
+        let d = opts.n_out_coords;
+        let pairs = n as f64 * n as f64;
+        let matrices = pair_bytes as u64 * (n as u64 * n as u64);
+        let mut coarse_matrices = 0;
+        if opts.multilevel && pair_bytes > 0 {
+            // Affinities of the largest coarse level are stored besides the full matrix
+            let coarser = n.div_ceil(2) as u64;
+            coarse_matrices = opts.affinity_bytes() as u64 * coarser * coarser;
+        }
+        // Coordinates, forces, inertias and weights
+        let state = ((3 * d + 1) * opts.state_bytes()) as u64 * n as u64;
+        let iteration_ns = match opts.batch_size {
+            Some(k) => n as f64 * k as f64 * NS_PER_SAMPLED_PARTNER,
+            None => pairs * (NS_PER_PAIR + NS_PER_PAIR_PER_COORD * d as f64),
+        };
+        // Neighbour lists of `--neighbors` springs
+        let springs = opts.neighbors.unwrap_or(0) as u64 * n as u64 * 8;
+        // Simulations running in parallel have their own state and coarse levels, the input is shared
+        let threads = crate::restarts::threads(opts);
+        let rounds = crate::restarts::count(opts).div_ceil(threads);
+        let total_iters = equivalent_iterations(opts, n, crate::schedule::total_iterations(opts));
+        let per_run = coarse_matrices + state + springs;
+        Estimate {
+            memory: matrices + threads as u64 * per_run + features as u64 * n as u64 * 8,
+            iteration_seconds: iteration_ns * 1e-9,
+            total_iters: rounds * total_iters,
+            on_the_fly: false,
+        }
+    }
+
+    /// Affinities are recomputed from `features` values per particle in each iteration
+    pub fn on_the_fly(opts: &Csvdimreduce, n: usize, features: usize) -> Estimate {
+        let feature_ns = NS_PER_PAIR_PER_FEATURE * features as f64;
+        let extra_ns = match opts.batch_size {
+            Some(k) => n as f64 * (k * OVERSAMPLING) as f64 * feature_ns,
+            None => n as f64 * n as f64 * (NS_PER_PAIR_ON_THE_FLY + feature_ns),
+        };
+        let mut e = Estimate::new(opts, n, 0, features + 1);
+        e.iteration_seconds += extra_ns * 1e-9;
+        e.on_the_fly = true;
+        e
+    }
+
+    pub fn total_seconds(&self) -> f64 {
+        self.iteration_seconds * self.total_iters as f64
+    }
+}
+
+/// Check limits for input with precomputed affinities,
+/// where `temporary_matrices` of `f64`s are alive while they are being built
+pub fn preflight(opts: &Csvdimreduce, n: usize, temporary_matrices: usize) -> anyhow::Result<()> {
+    let pair_bytes = 8 * temporary_matrices + opts.affinity_bytes();
+    check(opts, n, &Estimate::new(opts, n, pair_bytes, 0))
+}
+
+/// Resolve `--affinities` for table input with `features` columns and check limits for it
+pub fn choose_storage(
+    opts: &Csvdimreduce,
+    n: usize,
+    features: usize,
+) -> anyhow::Result<AffinityStorage> {
+    let dense = Estimate::new(opts, n, opts.affinity_bytes(), features);
+    let storage = match opts.affinities.unwrap_or_default() {
+        AffinityStorage::Auto if dense.memory > memory_limit(opts) => {
+            eprintln!(
+                "Affinity matrix for {n} particles would take {}, computing affinities on the fly instead",
+                format_bytes(dense.memory)
+            );
+            AffinityStorage::OnTheFly
+        }
+        AffinityStorage::Auto => AffinityStorage::Dense,
+        x => x,
+    };
+    if storage == AffinityStorage::OnTheFly {
+        check(opts, n, &Estimate::on_the_fly(opts, n, features))?;
+    } else {
+        check(opts, n, &dense)?;
+    }
+    Ok(storage)
+}
+
+fn memory_limit(opts: &Csvdimreduce) -> u64 {
+    opts.max_memory
+        .map(|x| x.0)
+        .or_else(available_memory)
+        .unwrap_or(DEFAULT_MAX_MEMORY)
+}
+
+/// Report the estimate for `n` particles and refuse to continue if it exceeds the limits
+fn check(opts: &Csvdimreduce, n: usize, e: &Estimate) -> anyhow::Result<()> {
+    let table = matches!(opts.input_mode.unwrap_or_default(), InputMode::Table);
+    if opts.debug {
+        println!(
+            "estimate particles={n} on_the_fly={} memory={} iteration_seconds={:.3} total_iters={} total_seconds={:.1}",
+            e.on_the_fly,
+            e.memory,
+            e.iteration_seconds,
+            e.total_iters,
+            e.total_seconds()
+        );
+    } else if e.memory >= NOTABLE_MEMORY || e.total_seconds() >= NOTABLE_RUNTIME {
+        eprintln!(
+            "Estimated memory usage {}, {} per iteration, {} for all {} iterations ({n} particles)",
+            format_bytes(e.memory),
+            format_duration(e.iteration_seconds),
+            format_duration(e.total_seconds()),
+            e.total_iters
+        );
+    }
+    if opts.force {
+        return Ok(());
+    }
+
+    let max_memory = memory_limit(opts);
+    if e.memory > max_memory {
+        let compact = if opts.quantize_affinities {
+            ""
+        } else {
+            "Use --quantize-affinities or --precision f32 to store affinities more compactly. "
+        };
+        let advice = if table && !e.on_the_fly {
+            "Use --affinities on-the-fly to avoid storing the affinity matrix or sample the input rows."
+        } else if table {
+            "Consider sampling the input rows."
+        } else {
+            "Memory grows quadratically with the number of input rows, so consider sampling them."
+        };
+        let advice = if e.on_the_fly {
+            advice.to_owned()
+        } else {
+            format!("{compact}{advice}")
+        };
+        anyhow::bail!(
+            "Estimated memory usage {} for {n} particles exceeds the limit of {}. {advice} \
+            Use --max-memory to change the limit or --force to proceed anyway.",
+            format_bytes(e.memory),
+            format_bytes(max_memory)
+        );
+    }
+    let max_runtime = opts.max_runtime.map_or(DEFAULT_MAX_RUNTIME, |x| x.0);
+    if e.total_seconds() > max_runtime {
+        let advice = if opts.batch_size.is_some() {
+            "Consider reducing the number of iterations or --batch-size."
+        } else {
+            "Each iteration takes time quadratic in the number of input rows. \
+            Consider reducing the number of iterations or using --batch-size to sample repelling particles."
+        };
+        anyhow::bail!(
+            "Estimated running time {} for {n} particles and {} iterations exceeds the limit of {}. {advice} \
+            Use --max-runtime to change the limit or --force to proceed anyway.",
+            format_duration(e.total_seconds()),
+            e.total_iters,
+            format_duration(max_runtime)
+        );
+    }
+    Ok(())
+}
+
+/// `MemAvailable` from `/proc/meminfo`, on Linux
+fn available_memory() -> Option<u64> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+    let line = meminfo.lines().find(|l| l.starts_with("MemAvailable:"))?;
+    let kib: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kib * 1024)
+}
+
+fn format_bytes(x: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut x = x as f64;
+    let mut unit = 0;
+    while x >= 1024.0 && unit + 1 < UNITS.len() {
+        x /= 1024.0;
+        unit += 1;
+    }
+    format!("{x:.1} {}", UNITS[unit])
+}
+
+fn format_duration(s: f64) -> String {
+    if s < 1.0 {
+        format!("{:.0} ms", s * 1e3)
+    } else if s < 120.0 {
+        format!("{s:.1} s")
+    } else if s < 2.0 * 3600.0 {
+        format!("{:.0} min", s / 60.0)
+    } else if s < 2.0 * 86400.0 {
+        format!("{:.1} h", s / 3600.0)
+    } else {
+        format!("{:.1} days", s / 86400.0)
+    }
+}
@@ -0,0 +1,46 @@
+use crate::text::TextColumn;
+use crate::Arr2;
+
+/// Per-particle input values that pairwise distances are computed from
+pub struct Features {
+    /// Numeric columns (including dates and cyclic values), one row per particle
+    pub numeric: Arr2,
+    /// Wrap-around period for each numeric column, if it is cyclic
+    pub periods: Vec<Option<f64>>,
+    pub text: Vec<TextColumn>,
+    /// Multiplier for text distances
+    pub text_weight: f64,
+}
+
+impl Features {
+    pub fn len(&self) -> usize {
+        self.numeric.nrows()
+    }
+
+    /// Features of the specified particles only
+    pub fn select(&self, rows: &[usize]) -> Features {
+        Features {
+            numeric: self.numeric.select(ndarray::Axis(0), rows),
+            periods: self.periods.clone(),
+            text: self.text.iter().map(|t| t.select(rows)).collect(),
+            text_weight: self.text_weight,
+        }
+    }
+
+    /// L1 distance between two particles. Cyclic columns use the shorter way around.
+    pub fn distance(&self, j: usize, k: usize) -> f64 {
+        let mut d = 0.0;
+        for (c, period) in self.periods.iter().enumerate() {
+            let mut x = (self.numeric[(j, c)] - self.numeric[(k, c)]).abs();
+            if let Some(p) = period {
+                x %= p;
+                x = x.min(p - x);
+            }
+            d += x;
+        }
+        for t in &self.text {
+            d += self.text_weight * t.distance(j, k);
+        }
+        d
+    }
+}
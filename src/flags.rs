@@ -1,10 +1,124 @@
-use std::{path::PathBuf, str::FromStr, collections::BTreeSet};
+use std::{path::{Path, PathBuf}, str::FromStr, collections::BTreeSet, fmt};
+
+use crate::compression::{Compression, OutputStream};
+use crate::text::TextMetric;
 
 
 #[derive(Debug)]
 pub struct ColumnsSpecifier(pub BTreeSet<usize>);
 #[derive(Debug)]
 pub struct DelimiterSpecifier(pub u8);
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FieldDelimiter {
+    /// Guess from the first lines of input
+    Auto,
+    Byte(u8),
+}
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColumnKind {
+    Number,
+    Text,
+    /// ISO 8601 date or date-time, converted to seconds since Unix epoch
+    Date,
+    /// Number (or `HH:MM[:SS]` time of day in hours) which wraps around with the given period
+    Cyclic(f64),
+}
+/// `COLUMNS=TYPE` annotation
+#[derive(Debug)]
+pub struct ColumnTypeSpec {
+    pub columns: BTreeSet<usize>,
+    pub kind: ColumnKind,
+}
+/// What to do with rows that cannot be used
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum OnError {
+    #[default]
+    Fail,
+    Skip,
+    Quarantine(PathBuf),
+}
+/// What to do with invalid numeric values
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ValuePolicy {
+    #[default]
+    Fail,
+    Missing,
+    Clip,
+}
+#[derive(Debug, Clone, Copy, Default)]
+pub enum InputMode {
+    #[default]
+    Table,
+    Matrix,
+    Triples,
+    Edges,
+}
+/// Whether to keep the `n`×`n` affinity matrix in memory
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum AffinityStorage {
+    /// Dense unless it would exceed the memory limit
+    #[default]
+    Auto,
+    Dense,
+    OnTheFly,
+}
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Precision {
+    #[default]
+    F64,
+    F32,
+}
+/// How to choose rows for `--landmarks`
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum LandmarkSelection {
+    Random,
+    /// Each next one with probability proportional to squared distance to the nearest chosen one
+    #[default]
+    KMeansPlusPlus,
+    /// Each next one is the farthest from already chosen ones
+    MaxMin,
+}
+/// Repulsion between two particles at distance `r`, multiplied by their affinity
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ForceLaw {
+    /// `1/r²`
+    #[default]
+    InverseSquare,
+    /// `1/r^p`
+    Power(f64),
+    /// `exp(-r/s)`
+    Exponential(f64),
+    /// `exp(-r²/(2s²))`
+    Gaussian(f64),
+    /// `1/(r²+ε²)`
+    Softened(f64),
+}
+/// How input-space distances are turned into affinities
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum AffinityTransform {
+    #[default]
+    Raw,
+    Squared,
+    /// `ln(1+d)`
+    Log,
+    /// Fraction of pairs of particles that are closer
+    Rank,
+}
+/// Amount of memory like `512M` or `16G`
+#[derive(Debug, Clone, Copy)]
+pub struct ByteSize(pub u64);
+/// Duration in seconds, parsed from strings like `90`, `30m` or `2h`
+#[derive(Debug, Clone, Copy)]
+pub struct TimeLimit(pub f64);
+/// Number of iterations in each phase of the simulation
+pub struct Iterations {
+    pub basic: usize,
+    pub warmup: usize,
+    /// For each squeezed coordinate
+    pub squeeze_rampup: usize,
+    pub squeeze_final: usize,
+    pub coords_to_squeeze: usize,
+}
 
 impl FromStr for ColumnsSpecifier {
     type Err = anyhow::Error;
@@ -19,26 +133,452 @@ impl FromStr for DelimiterSpecifier {
     type Err = anyhow::Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        if s.is_ascii() && s.len() == 1 {
-            Ok(DelimiterSpecifier(s.as_bytes()[0]))
+        let b = match s {
+            "\\t" => b'\t',
+            "\\n" => b'\n',
+            "\\r" => b'\r',
+            "\\0" => b'\0',
+            "\\\\" => b'\\',
+            _ if s.starts_with("\\x") && s.len() == 4 => u8::from_str_radix(&s[2..], 16)?,
+            _ if s.is_ascii() && s.len() == 1 => s.as_bytes()[0],
+            _ => anyhow::bail!("Expected exactly one ASCII character or an escape like `\\t` or `\\x1f`"),
+        };
+        Ok(DelimiterSpecifier(b))
+    }
+}
+
+impl FromStr for FieldDelimiter {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "auto" {
+            Ok(FieldDelimiter::Auto)
         } else {
-            Err(anyhow::anyhow!("Delimiter should be exactly one ASCII character"))
+            Ok(FieldDelimiter::Byte(DelimiterSpecifier::from_str(s)?.0))
+        }
+    }
+}
+
+impl FromStr for ColumnTypeSpec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((columns, kind)) = s.split_once('=') else {
+            anyhow::bail!("Column type should be specified like `3,5:7=text`");
+        };
+        let columns = ColumnsSpecifier::from_str(columns)?.0;
+        let kind = match kind {
+            "number" => ColumnKind::Number,
+            "text" => ColumnKind::Text,
+            "date" => ColumnKind::Date,
+            _ => match kind.strip_prefix("cyclic:").map(f64::from_str) {
+                Some(Ok(p)) if p > 0.0 && p.is_finite() => ColumnKind::Cyclic(p),
+                _ => anyhow::bail!("Column type should be `number`, `text`, `date` or `cyclic:PERIOD`"),
+            },
+        };
+        Ok(ColumnTypeSpec { columns, kind })
+    }
+}
+
+impl FromStr for TextMetric {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "tfidf" => TextMetric::TfIdf,
+            "ngram" => TextMetric::NGrams(3),
+            _ => match s.strip_prefix("ngram:").map(usize::from_str) {
+                Some(Ok(n)) if n > 0 => TextMetric::NGrams(n),
+                _ => anyhow::bail!("Text metric should be `ngram`, `ngram:N` or `tfidf`"),
+            },
+        })
+    }
+}
+
+impl FromStr for OnError {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "fail" => OnError::Fail,
+            "skip" => OnError::Skip,
+            _ => match s.strip_prefix("quarantine=") {
+                Some(path) if !path.is_empty() => OnError::Quarantine(path.into()),
+                _ => anyhow::bail!("--on-error should be `fail`, `skip` or `quarantine=PATH`"),
+            },
+        })
+    }
+}
+
+impl FromStr for ValuePolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "fail" => ValuePolicy::Fail,
+            "missing" => ValuePolicy::Missing,
+            "clip" => ValuePolicy::Clip,
+            _ => anyhow::bail!("Policy should be `fail`, `missing` or `clip`"),
+        })
+    }
+}
+
+impl FromStr for InputMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "table" => InputMode::Table,
+            "matrix" => InputMode::Matrix,
+            "triples" => InputMode::Triples,
+            "edges" => InputMode::Edges,
+            _ => anyhow::bail!("Input mode should be one of `table`, `matrix`, `triples` or `edges`"),
+        })
+    }
+}
+
+impl FromStr for AffinityStorage {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "auto" => AffinityStorage::Auto,
+            "dense" => AffinityStorage::Dense,
+            "on-the-fly" => AffinityStorage::OnTheFly,
+            _ => anyhow::bail!("Affinity storage should be `auto`, `dense` or `on-the-fly`"),
+        })
+    }
+}
+
+impl FromStr for Precision {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "f64" => Precision::F64,
+            "f32" => Precision::F32,
+            _ => anyhow::bail!("Precision should be `f64` or `f32`"),
+        })
+    }
+}
+
+impl FromStr for LandmarkSelection {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "random" => LandmarkSelection::Random,
+            "kmeans++" => LandmarkSelection::KMeansPlusPlus,
+            "maxmin" => LandmarkSelection::MaxMin,
+            _ => anyhow::bail!("Landmark selection should be `random`, `kmeans++` or `maxmin`"),
+        })
+    }
+}
+
+impl FromStr for ForceLaw {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "inverse-square" {
+            return Ok(ForceLaw::InverseSquare);
+        }
+        let parsed = s.split_once('=').and_then(|(law, x)| {
+            let x = f64::from_str(x).ok().filter(|x| *x > 0.0 && x.is_finite())?;
+            match law {
+                "power" => Some(ForceLaw::Power(x)),
+                "exp" => Some(ForceLaw::Exponential(x)),
+                "gaussian" => Some(ForceLaw::Gaussian(x)),
+                "softened" => Some(ForceLaw::Softened(x)),
+                _ => None,
+            }
+        });
+        match parsed {
+            Some(law) => Ok(law),
+            None => anyhow::bail!(
+                "Force law should be `inverse-square`, `power=P`, `exp=S`, `gaussian=S` or `softened=EPS` with a positive number"
+            ),
+        }
+    }
+}
+
+impl FromStr for AffinityTransform {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "raw" => AffinityTransform::Raw,
+            "squared" => AffinityTransform::Squared,
+            "log" => AffinityTransform::Log,
+            "rank" => AffinityTransform::Rank,
+            _ => anyhow::bail!("Affinity transform should be `raw`, `squared`, `log` or `rank`"),
+        })
+    }
+}
+
+impl FromStr for ByteSize {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim_end_matches(['B', 'b']);
+        let (number, multiplier) = match s.char_indices().last() {
+            Some((i, 'K' | 'k')) => (&s[..i], 1u64 << 10),
+            Some((i, 'M' | 'm')) => (&s[..i], 1 << 20),
+            Some((i, 'G' | 'g')) => (&s[..i], 1 << 30),
+            Some((i, 'T' | 't')) => (&s[..i], 1 << 40),
+            _ => (s, 1),
+        };
+        match f64::from_str(number) {
+            Ok(x) if x >= 0.0 && x.is_finite() => Ok(ByteSize((x * multiplier as f64) as u64)),
+            _ => anyhow::bail!("Memory size should be a number with optional K, M, G or T suffix, like `512M`"),
+        }
+    }
+}
+
+impl FromStr for TimeLimit {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (number, multiplier) = match s.char_indices().last() {
+            Some((i, 's')) => (&s[..i], 1.0),
+            Some((i, 'm')) => (&s[..i], 60.0),
+            Some((i, 'h')) => (&s[..i], 3600.0),
+            Some((i, 'd')) => (&s[..i], 86400.0),
+            _ => (s, 1.0),
+        };
+        match f64::from_str(number) {
+            Ok(x) if x >= 0.0 => Ok(TimeLimit(x * multiplier)),
+            _ => anyhow::bail!("Duration should be a number of seconds or have s, m, h or d suffix, like `30m`"),
+        }
+    }
+}
+
+// Display is the inverse of FromStr, for `--dump-config`
+
+impl fmt::Display for ColumnsSpecifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let columns: Vec<String> = self.0.iter().map(|c| c.to_string()).collect();
+        f.write_str(&columns.join(","))
+    }
+}
+
+impl fmt::Display for DelimiterSpecifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            b'\t' => f.write_str("\\t"),
+            b'\n' => f.write_str("\\n"),
+            b'\r' => f.write_str("\\r"),
+            b'\0' => f.write_str("\\0"),
+            b'\\' => f.write_str("\\\\"),
+            b if b.is_ascii_graphic() || b == b' ' => write!(f, "{}", b as char),
+            b => write!(f, "\\x{b:02x}"),
+        }
+    }
+}
+
+impl fmt::Display for FieldDelimiter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            FieldDelimiter::Auto => f.write_str("auto"),
+            FieldDelimiter::Byte(b) => DelimiterSpecifier(b).fmt(f),
+        }
+    }
+}
+
+impl fmt::Display for ColumnTypeSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let columns: Vec<String> = self.columns.iter().map(|c| c.to_string()).collect();
+        write!(f, "{}=", columns.join(","))?;
+        match self.kind {
+            ColumnKind::Number => f.write_str("number"),
+            ColumnKind::Text => f.write_str("text"),
+            ColumnKind::Date => f.write_str("date"),
+            ColumnKind::Cyclic(p) => write!(f, "cyclic:{p}"),
+        }
+    }
+}
+
+impl fmt::Display for TextMetric {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            TextMetric::NGrams(n) => write!(f, "ngram:{n}"),
+            TextMetric::TfIdf => f.write_str("tfidf"),
+        }
+    }
+}
+
+impl fmt::Display for OnError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OnError::Fail => f.write_str("fail"),
+            OnError::Skip => f.write_str("skip"),
+            OnError::Quarantine(path) => write!(f, "quarantine={}", path.display()),
+        }
+    }
+}
+
+impl fmt::Display for ValuePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ValuePolicy::Fail => "fail",
+            ValuePolicy::Missing => "missing",
+            ValuePolicy::Clip => "clip",
+        })
+    }
+}
+
+impl fmt::Display for InputMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            InputMode::Table => "table",
+            InputMode::Matrix => "matrix",
+            InputMode::Triples => "triples",
+            InputMode::Edges => "edges",
+        })
+    }
+}
+
+impl fmt::Display for AffinityStorage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            AffinityStorage::Auto => "auto",
+            AffinityStorage::Dense => "dense",
+            AffinityStorage::OnTheFly => "on-the-fly",
+        })
+    }
+}
+
+impl fmt::Display for Precision {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Precision::F64 => "f64",
+            Precision::F32 => "f32",
+        })
+    }
+}
+
+impl fmt::Display for LandmarkSelection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            LandmarkSelection::Random => "random",
+            LandmarkSelection::KMeansPlusPlus => "kmeans++",
+            LandmarkSelection::MaxMin => "maxmin",
+        })
+    }
+}
+
+impl fmt::Display for ForceLaw {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            ForceLaw::InverseSquare => f.write_str("inverse-square"),
+            ForceLaw::Power(p) => write!(f, "power={p}"),
+            ForceLaw::Exponential(s) => write!(f, "exp={s}"),
+            ForceLaw::Gaussian(s) => write!(f, "gaussian={s}"),
+            ForceLaw::Softened(eps) => write!(f, "softened={eps}"),
         }
     }
 }
 
+impl fmt::Display for AffinityTransform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            AffinityTransform::Raw => "raw",
+            AffinityTransform::Squared => "squared",
+            AffinityTransform::Log => "log",
+            AffinityTransform::Rank => "rank",
+        })
+    }
+}
+
+impl fmt::Display for ByteSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl fmt::Display for TimeLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}s", self.0)
+    }
+}
+
 impl Csvdimreduce {
+    /// Iteration counts of all phases, with defaults filled in
+    pub fn iterations(&self) -> Iterations {
+        let basic = self.n_iters.unwrap_or(100);
+        let squeeze_default = if self.retain_coords_from_squeezing.is_some() {
+            basic
+        } else {
+            0
+        };
+        Iterations {
+            basic,
+            warmup: self.warmup_iterations.unwrap_or(basic / 2),
+            squeeze_rampup: self.squeeze_rampup_iters.unwrap_or(squeeze_default),
+            squeeze_final: self.squeeze_final_iters.unwrap_or(squeeze_default),
+            coords_to_squeeze: self
+                .n_out_coords
+                .saturating_sub(self.retain_coords_from_squeezing.unwrap_or(self.n_out_coords)),
+        }
+    }
+
+    /// `--same-particle-force`, or its default
+    pub fn same_particle_force(&self) -> f64 {
+        self.same_particle_force.unwrap_or(crate::algorithm::DEFAULT_SAME_PARTICLE_FORCE)
+    }
+
+    /// Size of one element of the simulation state arrays
+    pub fn state_bytes(&self) -> usize {
+        match self.precision.unwrap_or_default() {
+            Precision::F64 => 8,
+            Precision::F32 => 4,
+        }
+    }
+
+    /// Size of one stored affinity
+    pub fn affinity_bytes(&self) -> usize {
+        if self.quantize_affinities {
+            2
+        } else {
+            self.state_bytes()
+        }
+    }
+
+    /// Type of a 1-based column number, according to `--column-type` annotations
+    pub fn column_kind(&self, column: usize) -> ColumnKind {
+        self.column_type
+            .iter()
+            .rev()
+            .find(|x| x.columns.contains(&column))
+            .map_or(ColumnKind::Number, |x| x.kind)
+    }
+
+    fn flexible(&self) -> bool {
+        self.flexible || !matches!(self.on_error, None | Some(OnError::Fail))
+    }
+
     pub fn get_csv_reader(&self) -> csv::ReaderBuilder {
         let mut b = csv::ReaderBuilder::new();
         if self.no_header {
             b.has_headers(false);
         }
-        if let Some(ref x) = self.delimiter {
-            b.delimiter(x.0);
+        if let Some(FieldDelimiter::Byte(x)) = self.delimiter {
+            b.delimiter(x);
         }
         if let Some(ref x) = self.record_delimiter {
             b.terminator(csv::Terminator::Any(x.0));
         }
+        if let Some(ref x) = self.quote {
+            b.quote(x.0);
+        }
+        if let Some(ref x) = self.escape {
+            b.escape(Some(x.0));
+        }
+        if let Some(ref x) = self.comment {
+            b.comment(Some(x.0));
+        }
+        if self.flexible() {
+            b.flexible(true);
+        }
         b
     }
     pub fn get_csv_writer(&self) -> csv::WriterBuilder {
@@ -46,12 +586,24 @@ impl Csvdimreduce {
         if self.no_header || self.no_output_header {
             b.has_headers(false);
         }
-        if let Some(ref x) = self.delimiter {
+        if let Some(ref x) = self.output_delimiter {
             b.delimiter(x.0);
+        } else if let Some(FieldDelimiter::Byte(x)) = self.delimiter {
+            b.delimiter(x);
         }
-        if let Some(ref x) = self.record_delimiter {
+        if let Some(x) = self.output_record_delimiter.as_ref().or(self.record_delimiter.as_ref()) {
             b.terminator(csv::Terminator::Any(x.0));
         }
+        if let Some(x) = self.output_quote.as_ref().or(self.quote.as_ref()) {
+            b.quote(x.0);
+        }
+        if let Some(x) = self.output_escape.as_ref().or(self.escape.as_ref()) {
+            b.escape(x.0);
+            b.double_quote(false);
+        }
+        if self.flexible() {
+            b.flexible(true);
+        }
         b
     }
 
@@ -63,15 +615,41 @@ impl Csvdimreduce {
         }
     }
 
-    pub fn get_ostream(&self) -> anyhow::Result<Box<dyn std::io::Write>> {
+    /// Open input stream, skipping byte order mark.
+    /// Also replaces `--delimiter auto` with the guessed delimiter.
+    pub fn open_input(&mut self) -> anyhow::Result<Box<dyn std::io::Read>> {
+        let f = crate::compression::decompress(self.get_istream()?)?;
+        let f = crate::dialect::strip_bom(f)?;
+        if self.delimiter != Some(FieldDelimiter::Auto) {
+            return Ok(f);
+        }
+        const SNIFF_BYTES: usize = 65536;
+        let (head, f) = crate::dialect::peek(f, SNIFF_BYTES)?;
+        let quote = self.quote.as_ref().map_or(b'"', |x| x.0);
+        let comment = self.comment.as_ref().map(|x| x.0);
+        let d = crate::dialect::sniff_delimiter(&head, quote, comment, head.len() < SNIFF_BYTES);
+        if self.debug {
+            println!("delimiter {:?}", d as char);
+        }
+        self.delimiter = Some(FieldDelimiter::Byte(d));
+        Ok(f)
+    }
+
+    pub fn get_ostream(&self) -> anyhow::Result<OutputStream> {
         if let Some(ref f) = self.output {
-            Ok(Box::new(std::fs::File::create(f)?))
+            create_output_file(f)
         } else {
-            Ok(Box::new(std::io::stdout()))
+            Ok(OutputStream::new(Box::new(std::io::stdout()), Compression::None)?)
         }
     }
 }
 
+/// Compressed according to file extension
+pub fn create_output_file(path: &Path) -> anyhow::Result<OutputStream> {
+    let f = std::io::BufWriter::new(std::fs::File::create(path)?);
+    Ok(OutputStream::new(Box::new(f), Compression::from_path(path))?)
+}
+
 xflags::xflags! {
     src "./src/flags.rs"
 
@@ -79,6 +657,9 @@ xflags::xflags! {
         /// List of columns to use as coordinates. First column is number 1. Parsing support ranges with steps like 3,4,10:5:100.
         /// See `number_range` Rust crate for details.
         /// Use `xsv headers your_file.csv` to find out column numbers.
+        /// With `--input-mode matrix`, those are columns of the distance matrix.
+        /// With `--input-mode triples`, those should be exactly three columns: first node, second node and distance.
+        /// With `--input-mode edges`, those should be two or three columns: source, target and optional weight.
         required columns: ColumnsSpecifier
         /// Number of output coordinates (new fields in CSV containing computed values)
         /// 
@@ -86,18 +667,46 @@ xflags::xflags! {
         required n_out_coords: usize
         /// Input csv file. Use stdin if absent.
         optional path: PathBuf
+        /// Save intermediate positions each `n` iterations. Without `--trajectory`, each
+        /// snapshot is a full copy of the output named `debugNNNNN.csv` in current directory
+        /// (compressed the same way as `-o`).
         optional --save-each-n-iters n : usize
+        /// Write particle positions at each iteration (or each `--save-each-n-iters`-th) to this file, plus the final ones.
+        /// CSV file has `iteration,phase,row_index,coord1..N` columns.
+        /// If the file name ends with `.npy`, write a `(frames, rows, coords)` NumPy array of f64s instead.
+        /// Additional `.gz` or `.zst` extension means compressing the file.
+        optional --trajectory path: PathBuf
         /// First line of the CSV is not headers
         optional --no-header
         /// Do not output CSV header even though input has headers
         optional --no-output-header
         /// Field delimiter in CSV files. Comma by default.
-        optional --delimiter delimiter : DelimiterSpecifier
+        /// Escapes are accepted: backslash followed by `t`, `n`, `r` or `0`, or by `x` and two hex digits like `x1f`.
+        /// `auto` means guessing it from the first lines of input.
+        optional --delimiter delimiter : FieldDelimiter
         /// Override line delimiter in CSV files.
         optional --record-delimiter delimiter : DelimiterSpecifier
-        /// Save file there instead of stdout
+        /// Quote character in CSV files. Default is double quote.
+        optional --quote char : DelimiterSpecifier
+        /// Escape character for quotes inside quoted fields, like backslash.
+        /// By default, quotes are escaped by doubling them.
+        optional --escape char : DelimiterSpecifier
+        /// Ignore input lines starting with this character, like `#`.
+        optional --comment char : DelimiterSpecifier
+        /// Allow records with varying number of fields
+        optional --flexible
+        /// Field delimiter for output files, if it should differ from input.
+        optional --output-delimiter delimiter : DelimiterSpecifier
+        /// Line delimiter for output files, if it should differ from input.
+        optional --output-record-delimiter delimiter : DelimiterSpecifier
+        /// Quote character for output files, if it should differ from input.
+        optional --output-quote char : DelimiterSpecifier
+        /// Escape character for output files, if it should differ from input.
+        optional --output-escape char : DelimiterSpecifier
+        /// Save file there instead of stdout. `.gz` or `.zst` extension means compressing the output.
+        /// Input files (and stdin) compressed with gzip or zstd are decompressed automatically.
         optional -o,--output path: PathBuf
-        /// Initial particle positions
+        /// Initial particle positions. The same seed, input, options and version give the same output on all platforms.
         optional --random-seed seed: u64
         /// Use this column as weights
         optional -w,--weight column_number: usize
@@ -138,6 +747,155 @@ xflags::xflags! {
         optional --debug
         /// Automatically normalize the data
         optional -N,--normalize
+        /// Annotate some of the selected columns with a type, like `7,9=text`. Can be repeated.
+        /// `number` (default) - parsed as a float, contributes absolute difference to distances;
+        /// `text` - contributes text distance (from 0 to 1) multiplied by `--text-weight`;
+        /// `date` - ISO 8601 date or date-time like `2023-08-15T12:30:00+02:00`, converted to seconds since Unix epoch;
+        /// `cyclic:PERIOD` - number that wraps around, like `cyclic:24` for hour of day or `cyclic:360` for bearing.
+        /// Times of day like `23:30` are converted to hours.
+        repeated --column-type spec: ColumnTypeSpec
+        /// How to compare text columns: `ngram` (Jaccard distance of character trigrams),
+        /// `ngram:N` (the same with N-grams) or `tfidf` (cosine distance of TF-IDF vectors of words).
+        /// Default is `ngram`.
+        optional --text-metric metric: TextMetric
+        /// Multiplier for text distances, relative to numeric columns. Default is 1.
+        optional --text-weight w: f64
+        /// How to interpret the input file:
+        /// `table` (default) - rows are particles, distances are computed from selected columns;
+        /// `matrix` - square matrix of precomputed distances, one row per particle;
+        /// `triples` - sparse distances as `i,j,d` records. Missing pairs get the maximum distance;
+        /// `edges` - undirected graph edge list. Distances are shortest path lengths, output is one row per node.
+        optional --input-mode mode: InputMode
+        /// Read row labels to output along with coordinates from this CSV file instead of the input file.
+        /// For `triples` and `edges` input modes, node names then should be 0-based record numbers in this file.
+        /// Without this option, `matrix` mode outputs non-selected columns (or column names from header as labels).
+        optional --labels path: PathBuf
+        /// For `--input-mode edges`, distance between nodes of disconnected components.
+        /// Default is 1.5 times the largest distance within components.
+        optional --disconnected-distance d: f64
+        /// Decimal separator in input numbers, like `,` for `3,14`. Default is `.`.
+        optional --decimal-separator c: char
+        /// Characters to ignore in input numbers, like thousands separators in `1 234 567` or `1'234.5`.
+        optional --grouping-separators chars: String
+        /// Remove currency signs or units before and after input numbers, like in `$12.5` or `12 kg`.
+        /// Percent (`%`) and permille (`‰`) suffixes also scale numbers down.
+        optional --strip-units
+        /// What to do with rows that fail to parse, have wrong number of fields or contain non-finite values:
+        /// `fail` (default) - abort; `skip` - exclude them from the simulation and report the count to stderr;
+        /// `quarantine=PATH` - like `skip`, but also write them to PATH with an additional leading `error` column.
+        optional --on-error policy: OnError
+        /// Write the rows excluded by `--on-error` to the output with blank coordinates
+        optional --keep-skipped
+        /// What to do with `NaN` or infinite values in the selected columns:
+        /// `fail` (default) - treat the row as bad (see `--on-error`); `missing` - replace with column average;
+        /// `clip` - replace infinities with the largest or smallest finite value of the column, NaNs with average.
+        optional --non-finite policy: ValuePolicy
+        /// What to do with zero, negative or non-finite `--weight` values. Policies are like in `--non-finite`,
+        /// but the range and average are computed from valid (positive) weights.
+        /// Rows with zero weight are thus errors by default, use `--on-error skip` to exclude them.
+        optional --bad-weights policy: ValuePolicy
+        /// Refuse to start if estimated memory usage exceeds this, like `512M` or `16G`.
+        /// Default is available physical memory (or 8G if it cannot be determined).
+        optional --max-memory size: ByteSize
+        /// Refuse to start if estimated running time exceeds this, like `90s`, `30m` or `2h`. Default is `1h`.
+        optional --max-runtime duration: TimeLimit
+        /// Proceed even if estimates exceed `--max-memory` or `--max-runtime`.
+        /// Estimates of at least 1 GiB or 10 seconds are printed to stderr in any case, smaller ones only with `--debug`.
+        optional --force
+        /// How to store pairwise affinities for table input:
+        /// `dense` - precompute the matrix, which takes memory quadratic in the number of rows;
+        /// `on-the-fly` - keep only the selected column values and recompute affinities in each iteration, which is slower;
+        /// `auto` (default) - `dense` unless the matrix would exceed `--max-memory`.
+        optional --affinities storage: AffinityStorage
+        /// Floating point type for coordinates, forces and stored affinities: `f64` (default) or `f32`.
+        /// `f32` halves memory usage at the cost of precision.
+        optional --precision type: Precision
+        /// Store affinities as 16-bit fixed point numbers, scaled to the largest affinity.
+        /// Takes a quarter of the memory of `f64` storage.
+        optional --quantize-affinities
+        /// Stochastic optimizer: estimate repulsion of each particle from this number of partners
+        /// sampled in each iteration (preferring ones with larger affinity) instead of all other particles.
+        /// Makes iterations linear in the number of rows. Sampling is seeded by `--random-seed`.
+        /// With `k` of at least the number of rows minus one, exact repulsion is computed instead.
+        optional --batch-size k: usize
+        /// Simulate only this number of rows (landmarks), then place each other row near its nearest
+        /// landmark in input space, shifted towards the second nearest one.
+        /// Other rows are only interpolated this way, their positions are not refined by the simulation.
+        /// Output still contains all rows. Trajectories and snapshots contain only landmarks.
+        optional --landmarks m: usize
+        /// How to choose landmarks: `random`, `kmeans++` (default) or `maxmin` (each next is the farthest from chosen ones)
+        optional --landmark-selection method: LandmarkSelection
+        /// Coarse-to-fine simulation: repeatedly merge each particle with its nearest neighbour into a heavier one,
+        /// lay out the coarsest level with all the usual phases, then split particles back level by level
+        /// and refine their positions with short runs. Helps large inputs avoid tangled clusters.
+        optional --multilevel
+        /// Stop coarsening at this number of particles. Default is 100.
+        optional --coarsest n: usize
+        /// Number of refinement iterations after each level is split back. Default is 10.
+        optional --level-iters k: usize
+        /// Attract each particle to this number of its nearest neighbours in input space with springs,
+        /// which makes clusters tighter and better separated
+        optional --neighbors k: usize
+        /// Strength of `--neighbors` springs, relative to average affinity like `--central-force`. Default is 3000.
+        optional --spring-constant s: f64
+        /// Length of unstretched `--neighbors` springs in output coordinates. Shorter springs push particles apart.
+        /// Default is 0.
+        optional --spring-length l: f64
+        /// Repulsion between particles at distance r, multiplied by their affinity:
+        /// `inverse-square` (default) - 1/r²; `power=P` - 1/r^P; `exp=S` - exp(-r/S);
+        /// `gaussian=S` - exp(-r²/(2S²)); `softened=EPS` - 1/(r²+EPS²), which stays finite for overlapping particles.
+        /// Faster decay preserves local structure better, slower decay preserves global structure.
+        /// `exp` and `gaussian` repel weakly at long range, so they need much lower `--central-force` (like 0.1)
+        /// to use the whole output range.
+        optional --force-law law: ForceLaw
+        /// Transform input distances before adding `--same-particle-force` to get affinities:
+        /// `raw` (default); `squared`; `log` - ln(1+d); `rank` - fraction of pairs of rows that are closer,
+        /// which ignores the scale of distances.
+        optional --affinity-transform t: AffinityTransform
+        /// Read simulation phases from this TOML (or `.json`) file instead of `-n`, `-r`, `-R`, `-S`
+        /// and other iteration and rate options. Each `[[phase]]` sets `iterations`, `rate`
+        /// and optionally `name`, `central_force`, `retain_coords` (coordinates not squeezed),
+        /// `squeeze_force` (for the first squeezed coordinate), `squeezed_force` (for the rest),
+        /// `inertia` and `reset_inertia`. Forces are relative to average affinity, like `-c`.
+        /// Rates and forces are numbers or curves like `{shape='cosine', from=0.01, to=0.0002}`
+        /// with shape `constant`, `linear`, `exponential`, `cosine` or `sqrt-lerp`.
+        /// A list of curves with `until=ITERATION` applies them one after another.
+        optional --schedule path: PathBuf
+        /// Run this many independent simulations, with `--random-seed` incremented for each one,
+        /// and output the one with the best trustworthiness (how few of the nearest neighbours on the map
+        /// are far away in input space). Scores are printed to stderr.
+        optional --restarts count: usize
+        /// Output rows of all `--restarts`, each time with a `restart` column numbered from 1 before the coordinates
+        optional --all-restarts
+        /// Run this many simulations with `--random-seed` incremented for each one, align them to the first one
+        /// by rotation, reflection and translation (Procrustes) and output mean coordinates of each row,
+        /// followed by a `position_sd` column: standard deviation of its position across the runs.
+        optional --stability count: usize
+        /// With `--stability`, also output a `neighbor_jaccard` column: Jaccard similarity of sets of this many
+        /// nearest neighbours of each row on the map, averaged over all pairs of runs.
+        optional --stability-neighbors k: usize
+        /// Number of simulations run in parallel. Default is the number of CPUs. Does not affect the output.
+        optional --threads count: usize
+        /// Read options from this TOML file, like `n-iters = 1000` or `column-type = ['2=text', '5=date']`.
+        /// Keys are long option names and values are as on the command line, `true` for switches.
+        /// Options specified on the command line take precedence.
+        optional --config path: PathBuf
+        /// Built-in set of options, overridden by `--config` and the command line:
+        /// `fast` - fewer iterations with a faster rate and `--multilevel`;
+        /// `quality` - more iterations and `--neighbors` springs;
+        /// `2d-map` - squeeze all coordinates but two (specify 3 or 4 output coordinates).
+        optional --preset name: String
+        /// Print all options as a `--config` file, with defaults filled in, and exit
+        optional --dump-config
+        /// Write a JSON file next to `-o` output (with `.json` appended to its name) recording how it was produced:
+        /// version, resolved options, input file size and SHA-256, selected columns, normalization statistics,
+        /// iterations done in each phase and running time.
+        optional --metadata
+        /// Repeat the run recorded in this `--metadata` file. Options specified on the command line
+        /// (but not positional arguments) take precedence over recorded ones. Output files (`-o`, `--metadata`,
+        /// `--trajectory`, `--save-each-n-iters` and the `--on-error quarantine` file, which becomes `skip`) are not replayed,
+        /// the result goes to stdout unless `-o` is given.
+        optional --replay path: PathBuf
     }
 }
 // generated start
@@ -150,10 +908,19 @@ pub struct Csvdimreduce {
     pub path: Option<PathBuf>,
 
     pub save_each_n_iters: Option<usize>,
+    pub trajectory: Option<PathBuf>,
     pub no_header: bool,
     pub no_output_header: bool,
-    pub delimiter: Option<DelimiterSpecifier>,
+    pub delimiter: Option<FieldDelimiter>,
     pub record_delimiter: Option<DelimiterSpecifier>,
+    pub quote: Option<DelimiterSpecifier>,
+    pub escape: Option<DelimiterSpecifier>,
+    pub comment: Option<DelimiterSpecifier>,
+    pub flexible: bool,
+    pub output_delimiter: Option<DelimiterSpecifier>,
+    pub output_record_delimiter: Option<DelimiterSpecifier>,
+    pub output_quote: Option<DelimiterSpecifier>,
+    pub output_escape: Option<DelimiterSpecifier>,
     pub output: Option<PathBuf>,
     pub random_seed: Option<u64>,
     pub weight: Option<usize>,
@@ -172,6 +939,47 @@ pub struct Csvdimreduce {
     pub warmup_iterations: Option<usize>,
     pub debug: bool,
     pub normalize: bool,
+    pub column_type: Vec<ColumnTypeSpec>,
+    pub text_metric: Option<TextMetric>,
+    pub text_weight: Option<f64>,
+    pub input_mode: Option<InputMode>,
+    pub labels: Option<PathBuf>,
+    pub disconnected_distance: Option<f64>,
+    pub decimal_separator: Option<char>,
+    pub grouping_separators: Option<String>,
+    pub strip_units: bool,
+    pub on_error: Option<OnError>,
+    pub keep_skipped: bool,
+    pub non_finite: Option<ValuePolicy>,
+    pub bad_weights: Option<ValuePolicy>,
+    pub max_memory: Option<ByteSize>,
+    pub max_runtime: Option<TimeLimit>,
+    pub force: bool,
+    pub affinities: Option<AffinityStorage>,
+    pub precision: Option<Precision>,
+    pub quantize_affinities: bool,
+    pub batch_size: Option<usize>,
+    pub landmarks: Option<usize>,
+    pub landmark_selection: Option<LandmarkSelection>,
+    pub multilevel: bool,
+    pub coarsest: Option<usize>,
+    pub level_iters: Option<usize>,
+    pub neighbors: Option<usize>,
+    pub spring_constant: Option<f64>,
+    pub spring_length: Option<f64>,
+    pub force_law: Option<ForceLaw>,
+    pub affinity_transform: Option<AffinityTransform>,
+    pub schedule: Option<PathBuf>,
+    pub restarts: Option<usize>,
+    pub all_restarts: bool,
+    pub stability: Option<usize>,
+    pub stability_neighbors: Option<usize>,
+    pub threads: Option<usize>,
+    pub config: Option<PathBuf>,
+    pub preset: Option<String>,
+    pub dump_config: bool,
+    pub metadata: bool,
+    pub replay: Option<PathBuf>,
 }
 
 impl Csvdimreduce {
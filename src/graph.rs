@@ -0,0 +1,122 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+use crate::affinities::{Affinities, DistanceTransform};
+use crate::flags::Csvdimreduce;
+use anyhow::Context;
+
+use crate::input::{read_records, Input, NumberFormat};
+use crate::matrix::{parse_distance, reject_table_options, select_fields, Nodes};
+use crate::{diagnostics, Arr2};
+
+/// Undirected graph from `source,target[,weight]` edge list. Distances between nodes are
+/// shortest path lengths, where each edge has length 1 or the specified weight.
+pub fn read_edges(opts: &Csvdimreduce, f: Box<dyn std::io::Read>) -> anyhow::Result<Input> {
+    reject_table_options(opts)?;
+    if !(2..=3).contains(&opts.columns.0.len()) {
+        anyhow::bail!(
+            "Edges input mode requires 2 or 3 columns: source, target and optional weight"
+        );
+    }
+    if let Some(d) = opts.disconnected_distance {
+        if !d.is_finite() || d < 0.0 {
+            anyhow::bail!("--disconnected-distance {d} is not a finite non-negative number");
+        }
+    }
+    let (header, edge_records) = read_records(opts, f)?;
+
+    let fmt = NumberFormat::new(opts);
+    let mut nodes = Nodes::new(opts, header.clone())?;
+    let mut edges = Vec::with_capacity(edge_records.len());
+    for (j, record) in edge_records.iter().enumerate() {
+        let (a, b, w) = match select_fields(opts, j, record)?[..] {
+            [a, b] => (a, b, 1.0),
+            [a, b, w] => {
+                let w = parse_distance(w, &fmt).with_context(|| {
+                    let column = *opts.columns.0.last().unwrap();
+                    format!(
+                        "Failed to parse {}",
+                        diagnostics::field_location(header.as_ref(), j, record, column)
+                    )
+                })?;
+                (a, b, w)
+            }
+            _ => unreachable!(),
+        };
+        let at = || diagnostics::record_location(j, record);
+        edges.push((
+            nodes.resolve(a).with_context(at)?,
+            nodes.resolve(b).with_context(at)?,
+            w,
+        ));
+    }
+
+    let mut input = nodes.into_input(opts);
+    let n = input.records.len();
+    crate::estimate::preflight(opts, n, 1)?;
+    let mut adjacency = vec![Vec::<(usize, f64)>::new(); n];
+    for (a, b, w) in edges {
+        if a != b {
+            adjacency[a].push((b, w));
+            adjacency[b].push((a, w));
+        }
+    }
+
+    let mut distances = Arr2::from_elem((n, n), f64::INFINITY);
+    for j in 0..n {
+        shortest_paths(&adjacency, j, distances.row_mut(j));
+    }
+
+    let max_distance = distances
+        .iter()
+        .copied()
+        .filter(|x| x.is_finite())
+        .fold(0.0f64, f64::max);
+    let disconnected_distance = opts.disconnected_distance.unwrap_or(if max_distance > 0.0 {
+        1.5 * max_distance
+    } else {
+        1.0
+    });
+    if opts.debug {
+        println!("graph nodes={n} max_distance={max_distance} disconnected_distance={disconnected_distance}");
+    }
+
+    let distance = |j, k| {
+        let d: f64 = distances[(j, k)];
+        if d.is_finite() {
+            d
+        } else {
+            disconnected_distance
+        }
+    };
+    let transform = DistanceTransform::new(opts, n, distance);
+    let same_particle_force = opts.same_particle_force();
+    input.affinities = Affinities::build(opts, n, |j, k| {
+        transform.apply(distance(j, k)) + same_particle_force
+    });
+    Ok(input)
+}
+
+/// Dijkstra's algorithm. Distances are non-negative, so their bit patterns sort the same as values.
+fn shortest_paths(
+    adjacency: &[Vec<(usize, f64)>],
+    start: usize,
+    mut distances: ndarray::ArrayViewMut1<'_, f64>,
+) {
+    let mut queue = BinaryHeap::new();
+    distances[start] = 0.0;
+    queue.push(Reverse((0.0f64.to_bits(), start)));
+    while let Some(Reverse((d, j))) = queue.pop() {
+        let d = f64::from_bits(d);
+        if d > distances[j] {
+            continue;
+        }
+        for &(k, w) in &adjacency[j] {
+            let nd = d + w;
+            if nd < distances[k] {
+                distances[k] = nd;
+                queue.push(Reverse((nd.to_bits(), k)));
+            }
+        }
+    }
+}
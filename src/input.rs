@@ -0,0 +1,652 @@
+use anyhow::Context;
+use trimothy::TrimSlice;
+
+use crate::affinities::{Affinities, DistanceTransform};
+use crate::features::Features;
+use crate::flags::{AffinityStorage, ColumnKind, Csvdimreduce, InputMode, OnError, ValuePolicy};
+use crate::landmarks::Landmarks;
+use crate::text::{TextColumn, TextMetric};
+use crate::{algorithm, diagnostics, Arr1, Arr2};
+
+/// Everything needed to run the simulation and write the output
+pub struct Input {
+    pub header: Option<csv::ByteRecord>,
+    /// Fields to be written after the coordinates, one record per particle
+    pub records: Vec<csv::ByteRecord>,
+    /// Records excluded from the simulation, to be written with blank coordinates
+    /// before the particle with the specified index
+    pub skipped: Vec<(usize, csv::ByteRecord)>,
+    pub weights: Option<Arr1>,
+    pub affinities: Affinities,
+    /// All rows of the table, if only landmarks are simulated
+    pub landmarks: Option<Landmarks>,
+    /// Statistics of `--normalize`d columns, empty without it
+    pub normalization: Vec<Normalization>,
+}
+
+impl Input {
+    /// Records to output for each particle, with affinities to be filled in later
+    pub fn labels(header: Option<csv::ByteRecord>, records: Vec<csv::ByteRecord>) -> Input {
+        Input {
+            header,
+            records,
+            skipped: vec![],
+            weights: None,
+            affinities: Affinities::Dense(Arr2::zeros((0, 0))),
+            landmarks: None,
+            normalization: vec![],
+        }
+    }
+}
+
+/// How a numeric column was normalized: `(x - mean) / scale`
+pub struct Normalization {
+    /// 1-based column number
+    pub column: usize,
+    pub mean: f64,
+    pub scale: f64,
+}
+
+/// `f` is the main input stream
+pub fn read_input(opts: &Csvdimreduce, f: Box<dyn std::io::Read>) -> anyhow::Result<Input> {
+    match opts.input_mode.unwrap_or_default() {
+        InputMode::Table => read_table(opts, f),
+        InputMode::Matrix => crate::matrix::read_dense(opts, f),
+        InputMode::Triples => crate::matrix::read_triples(opts, f),
+        InputMode::Edges => crate::graph::read_edges(opts, f),
+    }
+}
+
+/// How to parse numbers in input fields
+pub struct NumberFormat {
+    pub decimal_separator: char,
+    /// Characters to remove from numbers, like thousands separators
+    pub grouping_separators: String,
+    /// Remove text before and after the number, like currency signs.
+    /// `%` and `‰` suffixes also scale the number.
+    pub strip_units: bool,
+}
+
+impl NumberFormat {
+    pub fn new(opts: &Csvdimreduce) -> NumberFormat {
+        NumberFormat {
+            decimal_separator: opts.decimal_separator.unwrap_or('.'),
+            grouping_separators: opts.grouping_separators.clone().unwrap_or_default(),
+            strip_units: opts.strip_units,
+        }
+    }
+
+    pub fn parse(&self, field: &[u8]) -> anyhow::Result<f64> {
+        let s = std::str::from_utf8(field.trim())?;
+        if self.decimal_separator == '.' && self.grouping_separators.is_empty() && !self.strip_units
+        {
+            return Ok(s.parse()?);
+        }
+
+        let is_numeric = |c: char| c.is_ascii_digit() || c == self.decimal_separator;
+        let mut core = s;
+        let mut sign = 1.0;
+        let mut scale = 1.0;
+        // Values like `NaN` or `inf` have no digits and are left as is
+        if self.strip_units && s.contains(|c: char| c.is_ascii_digit()) {
+            let start = s.find(is_numeric).unwrap_or(0);
+            let end = s.rfind(is_numeric).map_or(s.len(), |i| i + 1);
+            let (prefix, suffix) = (&s[..start], s[end..].trim());
+            if prefix.contains('-') {
+                sign = -1.0;
+            }
+            match suffix {
+                "%" => scale = 0.01,
+                "‰" => scale = 0.001,
+                _ => (),
+            }
+            core = &s[start..end];
+        }
+        let normalized: String = core
+            .chars()
+            .filter(|c| !self.grouping_separators.contains(*c))
+            .map(|c| if c == self.decimal_separator { '.' } else { c })
+            .collect();
+        match normalized.parse::<f64>() {
+            Ok(x) => Ok(sign * scale * x),
+            Err(_) => {
+                anyhow::bail!("Cannot parse `{s}` as a number (normalized to `{normalized}`)")
+            }
+        }
+    }
+}
+
+/// ISO 8601 date (`2023-08-15`) or date-time (`2023-08-15T12:30:00.5+02:00`, space instead of `T`
+/// is also accepted) to seconds since Unix epoch. Date-times without offset are treated as UTC.
+pub fn parse_date(field: &[u8]) -> anyhow::Result<f64> {
+    let s = std::str::from_utf8(field.trim())?;
+    let bad = || anyhow::anyhow!("Invalid ISO 8601 date: `{s}`");
+    let (date, time) = match s.find(['T', 't', ' ']) {
+        Some(i) => (&s[..i], Some(&s[i + 1..])),
+        None => (s, None),
+    };
+    let mut parts = date.splitn(3, '-');
+    let (Some(y), Some(m), Some(d)) = (parts.next(), parts.next(), parts.next()) else {
+        return Err(bad());
+    };
+    let y: i64 = y.parse().map_err(|_| bad())?;
+    let m: i64 = m.parse().map_err(|_| bad())?;
+    let d: i64 = d.parse().map_err(|_| bad())?;
+    if !(1..=12).contains(&m) || d < 1 || d > days_in_month(y, m) {
+        return Err(bad());
+    }
+    let mut seconds = days_from_civil(y, m, d) as f64 * 86400.0;
+    if let Some(time) = time {
+        let (time, offset) = if let Some(t) = time.strip_suffix(['Z', 'z']) {
+            (t, 0.0)
+        } else if let Some(i) = time.rfind(['+', '-']) {
+            let sign = if time.as_bytes()[i] == b'-' {
+                -1.0
+            } else {
+                1.0
+            };
+            let offset = time[i + 1..].replace(':', "");
+            if offset.len() != 4 {
+                return Err(bad());
+            }
+            let hh: f64 = offset[..2].parse().map_err(|_| bad())?;
+            let mm: f64 = offset[2..].parse().map_err(|_| bad())?;
+            (&time[..i], sign * (hh * 3600.0 + mm * 60.0))
+        } else {
+            (time, 0.0)
+        };
+        seconds += parse_time_of_day(time).ok_or_else(bad)? * 3600.0 - offset;
+    }
+    Ok(seconds)
+}
+
+/// `HH:MM[:SS[.fff]]` to hours
+fn parse_time_of_day(s: &str) -> Option<f64> {
+    let mut parts = s.splitn(3, ':');
+    let h: f64 = parts.next()?.parse().ok()?;
+    let m: f64 = parts.next()?.parse().ok()?;
+    let sec: f64 = parts.next().map_or(Some(0.0), |x| x.parse().ok())?;
+    if !(0.0..60.0).contains(&m) || !(0.0..61.0).contains(&sec) {
+        return None;
+    }
+    // `24:00` is the end of the day
+    let end_of_day = h == 24.0 && m == 0.0 && sec == 0.0;
+    if !(0.0..24.0).contains(&h) && !end_of_day {
+        return None;
+    }
+    Some(h + m / 60.0 + sec / 3600.0)
+}
+
+fn days_in_month(y: i64, m: i64) -> i64 {
+    match m {
+        2 if y % 4 == 0 && (y % 100 != 0 || y % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Number of days since 1970-01-01 in proleptic Gregorian calendar
+fn days_from_civil(y: i64, m: i64, d: i64) -> i64 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * (m + if m > 2 { -3 } else { 9 }) + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+/// Plain number or `HH:MM[:SS]` time of day, in hours
+pub fn parse_cyclic(field: &[u8], fmt: &NumberFormat) -> anyhow::Result<f64> {
+    let s = std::str::from_utf8(field.trim())?;
+    if s.contains(':') {
+        parse_time_of_day(s).ok_or_else(|| anyhow::anyhow!("Invalid time of day: `{s}`"))
+    } else {
+        fmt.parse(field)
+    }
+}
+
+/// Read all records of a CSV file, also returning the header (if the file has it)
+pub fn read_records(
+    opts: &Csvdimreduce,
+    f: impl std::io::Read,
+) -> anyhow::Result<(Option<csv::ByteRecord>, Vec<csv::ByteRecord>)> {
+    let mut f = opts.get_csv_reader().from_reader(f);
+
+    let mut records = Vec::<csv::ByteRecord>::with_capacity(1024);
+    let header: Option<csv::ByteRecord> = if f.has_headers() {
+        Some(f.byte_headers()?.clone())
+    } else {
+        None
+    };
+    for record in f.into_byte_records() {
+        let record = record?;
+        records.push(record);
+    }
+    Ok((header, records))
+}
+
+/// Values of one table row
+struct Row {
+    numeric: Vec<f64>,
+    texts: Vec<String>,
+    weight: f64,
+}
+
+fn parse_row(
+    opts: &Csvdimreduce,
+    fmt: &NumberFormat,
+    header: Option<&csv::ByteRecord>,
+    width: Option<usize>,
+    j: usize,
+    record: &csv::ByteRecord,
+) -> anyhow::Result<Row> {
+    if let Some(width) = width {
+        if record.len() != width {
+            anyhow::bail!(
+                "{} has {} fields instead of {width}",
+                diagnostics::record_location(j, record),
+                record.len()
+            );
+        }
+    }
+    let row_at = || diagnostics::record_location(j, record);
+    diagnostics::check_columns("Field list", opts.columns.0.iter().copied(), record, row_at)?;
+    diagnostics::check_columns("Weight column", opts.weight, record, row_at)?;
+    let at = |i: usize| diagnostics::field_location(header, j, record, i);
+    let mut row = Row {
+        numeric: Vec::with_capacity(opts.columns.0.len()),
+        texts: vec![],
+        weight: 1.0,
+    };
+    for &i in &opts.columns.0 {
+        let field = &record[i - 1];
+        let kind = opts.column_kind(i);
+        if kind == ColumnKind::Text {
+            row.texts.push(String::from_utf8_lossy(field).into_owned());
+            continue;
+        }
+        let x = match kind {
+            ColumnKind::Date => parse_date(field),
+            ColumnKind::Cyclic(_) => parse_cyclic(field, fmt),
+            _ => fmt.parse(field),
+        }
+        .with_context(|| format!("Failed to parse {}", at(i)))?;
+        if !x.is_finite() && opts.non_finite.unwrap_or_default() == ValuePolicy::Fail {
+            anyhow::bail!("Non-finite value at {}", at(i));
+        }
+        row.numeric.push(x);
+    }
+    if let Some(i) = opts.weight {
+        row.weight = fmt
+            .parse(&record[i - 1])
+            .with_context(|| format!("Failed to parse weight at {}", at(i)))?;
+        if !valid_weight(row.weight) && opts.bad_weights.unwrap_or_default() == ValuePolicy::Fail {
+            anyhow::bail!("Weight should be a finite positive number at {}", at(i));
+        }
+    }
+    Ok(row)
+}
+
+fn valid_weight(x: f64) -> bool {
+    x.is_finite() && x > 0.0
+}
+
+/// Replace invalid values with the average of valid ones (`Missing`) or with the nearest
+/// end of their range (`Clip`). Values that cannot be clipped, like NaN, are treated as missing.
+fn apply_policy(
+    mut values: ndarray::ArrayViewMut1<'_, f64>,
+    policy: ValuePolicy,
+    valid: impl Fn(f64) -> bool,
+) {
+    if policy == ValuePolicy::Fail || values.iter().all(|&x| valid(x)) {
+        return;
+    }
+    let (mut sum, mut count) = (0.0, 0usize);
+    let (mut min, mut max) = (f64::INFINITY, f64::NEG_INFINITY);
+    for &x in values.iter().filter(|&&x| valid(x)) {
+        sum += x;
+        count += 1;
+        min = min.min(x);
+        max = max.max(x);
+    }
+    // Nothing to take replacements from, so fall back to neutral values
+    let average = if count > 0 { sum / count as f64 } else { 1.0 };
+    if count == 0 {
+        (min, max) = (average, average);
+    }
+    for x in values.iter_mut().filter(|x| !valid(**x)) {
+        *x = match policy {
+            ValuePolicy::Clip if *x >= max => max,
+            ValuePolicy::Clip if *x <= min => min,
+            _ => average,
+        };
+    }
+}
+
+fn read_table(opts: &Csvdimreduce, f: Box<dyn std::io::Read>) -> anyhow::Result<Input> {
+    let (header, all_records) = read_records(opts, f)?;
+    let on_error = opts.on_error.clone().unwrap_or_default();
+    let fmt = NumberFormat::new(opts);
+
+    let mut periods = Vec::new();
+    let mut numeric_columns = Vec::new();
+    let mut n_text = 0;
+    for &i in &opts.columns.0 {
+        match opts.column_kind(i) {
+            ColumnKind::Number | ColumnKind::Date => periods.push(None),
+            ColumnKind::Cyclic(p) => periods.push(Some(p)),
+            ColumnKind::Text => n_text += 1,
+        }
+        if opts.column_kind(i) != ColumnKind::Text {
+            numeric_columns.push(i);
+        }
+    }
+
+    // Checked once up front, as in lenient modes every row would be skipped otherwise
+    if let Some(first) = header.as_ref().or(all_records.first()) {
+        let at = || match header {
+            Some(ref h) => diagnostics::header_location(h),
+            None => diagnostics::record_location(0, first),
+        };
+        diagnostics::check_columns("Field list", opts.columns.0.iter().copied(), first, at)?;
+        diagnostics::check_columns("Weight column", opts.weight, first, at)?;
+    }
+
+    // Reader is flexible in lenient modes, so check record lengths here
+    let width = match on_error {
+        OnError::Fail => None,
+        _ => header.as_ref().or(all_records.first()).map(|x| x.len()),
+    };
+    let mut quarantine = if let OnError::Quarantine(ref path) = on_error {
+        let f = crate::flags::create_output_file(path)
+            .with_context(|| format!("Failed to create quarantine file {}", path.display()))?;
+        let mut f = opts.get_csv_writer().from_writer(f);
+        if let Some(ref h) = header {
+            f.write_field("error")?;
+            f.write_record(h)?;
+        }
+        Some(f)
+    } else {
+        None
+    };
+
+    let mut records = Vec::with_capacity(all_records.len());
+    let mut skipped = Vec::new();
+    let mut rows = Vec::with_capacity(all_records.len());
+    for (j, record) in all_records.into_iter().enumerate() {
+        match parse_row(opts, &fmt, header.as_ref(), width, j, &record) {
+            Ok(row) => {
+                rows.push(row);
+                records.push(record);
+            }
+            Err(e) if on_error == OnError::Fail => return Err(e),
+            Err(e) => {
+                if let Some(ref mut q) = quarantine {
+                    q.write_field(format!("{e:#}"))?;
+                    q.write_record(&record)?;
+                }
+                if opts.debug {
+                    eprintln!("Skipping: {e:#}");
+                }
+                skipped.push((records.len(), record));
+            }
+        }
+    }
+    if let Some(q) = quarantine {
+        crate::compression::finish_csv(q)?;
+    }
+    if !skipped.is_empty() {
+        eprintln!(
+            "Skipped {} of {} rows because of errors",
+            skipped.len(),
+            skipped.len() + records.len()
+        );
+    }
+    if !opts.keep_skipped {
+        skipped.clear();
+    }
+
+    let n_rows = records.len();
+    if opts.landmarks == Some(0) {
+        anyhow::bail!("--landmarks should be at least 1");
+    }
+    let n_simulated = opts.landmarks.map_or(n_rows, |m| m.min(n_rows));
+    let storage = crate::estimate::choose_storage(opts, n_simulated, periods.len() + n_text)?;
+    let mut inputvals = Arr2::zeros((n_rows, periods.len()));
+    let mut texts = vec![Vec::<String>::with_capacity(n_rows); n_text];
+    let mut weights = Arr1::zeros(n_rows);
+    for (j, row) in rows.into_iter().enumerate() {
+        for (c, x) in row.numeric.into_iter().enumerate() {
+            inputvals[(j, c)] = x;
+        }
+        for (c, x) in row.texts.into_iter().enumerate() {
+            texts[c].push(x);
+        }
+        weights[j] = row.weight;
+    }
+
+    for c in 0..periods.len() {
+        apply_policy(
+            inputvals.column_mut(c),
+            opts.non_finite.unwrap_or_default(),
+            |x| x.is_finite(),
+        );
+    }
+    apply_policy(
+        weights.view_mut(),
+        opts.bad_weights.unwrap_or_default(),
+        valid_weight,
+    );
+
+    let mut normalization = vec![];
+    if opts.normalize {
+        let scales = algorithm::normalize(inputvals.view_mut());
+        // Keep the wrap-around at the same place relative to values
+        for (p, &(_, scale)) in periods.iter_mut().zip(&scales) {
+            if let Some(p) = p {
+                *p /= scale;
+            }
+        }
+        normalization = numeric_columns
+            .into_iter()
+            .zip(scales)
+            .map(|(column, (mean, scale))| Normalization {
+                column,
+                mean,
+                scale,
+            })
+            .collect();
+    }
+    //println!("{inputvals}");
+    let text_metric = opts.text_metric.unwrap_or(TextMetric::NGrams(3));
+    let features = Features {
+        numeric: inputvals,
+        periods,
+        text: texts
+            .iter()
+            .map(|x| TextColumn::new(x, text_metric))
+            .collect(),
+        text_weight: opts.text_weight.unwrap_or(1.0),
+    };
+    let same_particle_force = opts.same_particle_force();
+    let header = header.filter(|_| !opts.no_output_header);
+    let weights = opts.weight.map(|_| weights);
+    if n_simulated < n_rows {
+        let input = crate::landmarks::landmark_input(
+            opts,
+            n_simulated,
+            header,
+            records,
+            skipped,
+            weights,
+            features,
+            storage,
+            same_particle_force,
+        );
+        return Ok(Input {
+            normalization,
+            ..input
+        });
+    }
+    Ok(Input {
+        header,
+        records,
+        skipped,
+        weights,
+        affinities: table_affinities(opts, storage, features, same_particle_force),
+        landmarks: None,
+        normalization,
+    })
+}
+
+pub fn table_affinities(
+    opts: &Csvdimreduce,
+    storage: AffinityStorage,
+    features: Features,
+    same_particle_force: f64,
+) -> Affinities {
+    let transform = DistanceTransform::new(opts, features.len(), |j, k| features.distance(j, k));
+    if storage == AffinityStorage::OnTheFly {
+        Affinities::OnTheFly {
+            features,
+            same_particle_force,
+            transform,
+        }
+    } else {
+        Affinities::build(opts, features.len(), |j, k| {
+            algorithm::particle_affinity(&features, j, k, same_particle_force, &transform)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn format(
+        decimal_separator: char,
+        grouping_separators: &str,
+        strip_units: bool,
+    ) -> NumberFormat {
+        NumberFormat {
+            decimal_separator,
+            grouping_separators: grouping_separators.to_owned(),
+            strip_units,
+        }
+    }
+
+    #[test]
+    #[allow(clippy::approx_constant)]
+    fn numbers() {
+        assert_eq!(format('.', "", false).parse(b" 2.5 ").unwrap(), 2.5);
+        assert_eq!(format(',', "", false).parse(b"3,14").unwrap(), 3.14);
+        assert_eq!(format(',', " ", false).parse(b"1 234,5").unwrap(), 1234.5);
+        assert_eq!(
+            format('.', ",", false).parse(b"1,234,567.5").unwrap(),
+            1234567.5
+        );
+        assert!(format('.', "", false).parse(b"3,14").is_err());
+        assert!(format('.', "", false).parse(b"12 kg").is_err());
+    }
+
+    #[test]
+    fn units() {
+        let fmt = format('.', "", true);
+        assert_eq!(fmt.parse(b"-$4").unwrap(), -4.0);
+        assert_eq!(fmt.parse(b"$-4.5").unwrap(), -4.5);
+        assert_eq!(fmt.parse(b"50%").unwrap(), 0.5);
+        assert_eq!(fmt.parse("5 ‰".as_bytes()).unwrap(), 0.005);
+        assert_eq!(fmt.parse(b"12 kg").unwrap(), 12.0);
+        assert_eq!(
+            format(',', " ", true).parse(b"1 234,5 EUR").unwrap(),
+            1234.5
+        );
+        assert!(fmt.parse(b"kg").is_err());
+    }
+
+    #[test]
+    fn nan_passthrough() {
+        for fmt in [format('.', "", false), format(',', " ", true)] {
+            assert!(fmt.parse(b"NaN").unwrap().is_nan());
+            assert_eq!(fmt.parse(b"-inf").unwrap(), f64::NEG_INFINITY);
+        }
+    }
+
+    #[test]
+    fn dates() {
+        assert_eq!(parse_date(b"1970-01-01").unwrap(), 0.0);
+        assert_eq!(parse_date(b"2000-03-01").unwrap(), 951868800.0);
+        assert_eq!(parse_date(b" 2024-02-29 ").unwrap(), 1709164800.0);
+        assert_eq!(parse_date(b"1969-12-31").unwrap(), -86400.0);
+        assert_eq!(parse_date(b"2023-08-15T12:30:00Z").unwrap(), 1692102600.0);
+        assert_eq!(
+            parse_date(b"2023-08-15 14:30:00+02:00").unwrap(),
+            1692102600.0
+        );
+        assert_eq!(
+            parse_date(b"2023-08-15T07:00:00.5-0530").unwrap(),
+            1692102600.5
+        );
+        assert_eq!(
+            parse_date(b"2023-08-15T24:00").unwrap(),
+            parse_date(b"2023-08-16").unwrap()
+        );
+    }
+
+    #[test]
+    fn invalid_dates() {
+        for s in [
+            "2023-02-29",
+            "2023-02-31",
+            "2024-02-30",
+            "1900-02-29",
+            "2023-04-31",
+            "2023-13-01",
+            "2023-00-10",
+            "2023-01-00",
+            "2023-01",
+            "15.08.2023",
+            "2023-08-15T24:59",
+            "2023-08-15T12:60",
+            "2023-08-15T12:00+2",
+        ] {
+            assert!(parse_date(s.as_bytes()).is_err(), "{s}");
+        }
+        assert!(parse_date(b"2000-02-29").is_ok());
+    }
+
+    #[test]
+    fn days() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(days_from_civil(1970, 3, 1), 59);
+        assert_eq!(days_from_civil(2000, 1, 1), 10957);
+        assert_eq!(days_from_civil(1600, 1, 1), -135140);
+        assert_eq!(
+            days_from_civil(2024, 12, 31) - days_from_civil(2024, 1, 1),
+            365
+        );
+    }
+
+    #[test]
+    fn times_of_day() {
+        assert_eq!(parse_time_of_day("00:00"), Some(0.0));
+        assert_eq!(parse_time_of_day("12:30"), Some(12.5));
+        assert_eq!(parse_time_of_day("06:00:36"), Some(6.01));
+        assert_eq!(parse_time_of_day("24:00"), Some(24.0));
+        assert_eq!(parse_time_of_day("24:00:00"), Some(24.0));
+        for s in [
+            "24:59", "24:00:01", "25:00", "-1:00", "12:60", "12", "12:xx",
+        ] {
+            assert_eq!(parse_time_of_day(s), None, "{s}");
+        }
+    }
+
+    #[test]
+    fn cyclic() {
+        let fmt = format('.', "", false);
+        assert_eq!(parse_cyclic(b"18:45", &fmt).unwrap(), 18.75);
+        assert_eq!(parse_cyclic(b"270", &fmt).unwrap(), 270.0);
+        assert!(parse_cyclic(b"24:30", &fmt).is_err());
+    }
+}
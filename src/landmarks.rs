@@ -0,0 +1,163 @@
+//! Simulating only a subset of rows and placing the rest relative to the nearest of them
+
+use ndarray::{ArrayView2, Axis};
+use rand::Rng;
+
+use crate::features::Features;
+use crate::flags::{AffinityStorage, Csvdimreduce, LandmarkSelection};
+use crate::input::{table_affinities, Input};
+use crate::{Arr1, Arr2};
+
+/// Largest fraction of the way from the nearest landmark to the second nearest one a row is placed at
+const SHIFT: f64 = 0.5;
+
+/// Everything about all rows of the table, when only landmarks are simulated
+pub struct Landmarks {
+    /// Indices of landmark rows, in the order of simulated particles
+    pub indices: Vec<usize>,
+    pub records: Vec<csv::ByteRecord>,
+    pub skipped: Vec<(usize, csv::ByteRecord)>,
+    pub features: Features,
+}
+
+/// Choose `m` landmark rows and make an input consisting only of them
+#[allow(clippy::too_many_arguments)]
+pub fn landmark_input(
+    opts: &Csvdimreduce,
+    m: usize,
+    header: Option<csv::ByteRecord>,
+    records: Vec<csv::ByteRecord>,
+    skipped: Vec<(usize, csv::ByteRecord)>,
+    weights: Option<Arr1>,
+    features: Features,
+    storage: AffinityStorage,
+    same_particle_force: f64,
+) -> Input {
+    let mut rng = crate::rng::seeded(opts);
+    let indices = choose(
+        opts.landmark_selection.unwrap_or_default(),
+        &features,
+        m,
+        &mut rng,
+    );
+    if opts.debug {
+        println!("landmarks {indices:?}");
+    }
+    let affinities = table_affinities(
+        opts,
+        storage,
+        features.select(&indices),
+        same_particle_force,
+    );
+    Input {
+        header,
+        records: indices.iter().map(|&j| records[j].clone()).collect(),
+        skipped: vec![],
+        weights: weights.as_ref().map(|w| w.select(Axis(0), &indices)),
+        affinities,
+        normalization: vec![],
+        landmarks: Some(Landmarks {
+            indices,
+            records,
+            skipped,
+            features,
+        }),
+    }
+}
+
+/// Sorted indices of `m` distinct rows
+fn choose(
+    method: LandmarkSelection,
+    features: &Features,
+    m: usize,
+    rng: &mut impl Rng,
+) -> Vec<usize> {
+    let n = features.len();
+    if method == LandmarkSelection::Random {
+        let mut chosen = rand::seq::index::sample(rng, n, m).into_vec();
+        chosen.sort_unstable();
+        return chosen;
+    }
+
+    let mut chosen = vec![crate::rng::index(rng, 0..n)];
+    let mut is_chosen = vec![false; n];
+    is_chosen[chosen[0]] = true;
+    // Distance to the nearest chosen row
+    let mut nearest = vec![f64::INFINITY; n];
+    while chosen.len() < m {
+        let last = *chosen.last().unwrap();
+        for (j, x) in nearest.iter_mut().enumerate() {
+            *x = x.min(features.distance(j, last));
+        }
+        let next = match method {
+            LandmarkSelection::MaxMin => {
+                let (j, &d) = nearest
+                    .iter()
+                    .enumerate()
+                    .max_by(|a, b| a.1.total_cmp(b.1))
+                    .unwrap();
+                (d > 0.0).then_some(j)
+            }
+            _ => {
+                // k-means++: probability is proportional to squared distance
+                let total: f64 = nearest.iter().map(|x| x * x).sum();
+                if total > 0.0 {
+                    let mut r = rng.gen_range(0.0..total);
+                    nearest.iter().position(|x| {
+                        r -= x * x;
+                        r < 0.0 && *x > 0.0
+                    })
+                } else {
+                    None
+                }
+            }
+        };
+        // All remaining rows duplicate chosen ones
+        let next = next.unwrap_or_else(|| is_chosen.iter().position(|x| !x).unwrap());
+        is_chosen[next] = true;
+        chosen.push(next);
+    }
+    chosen.sort_unstable();
+    chosen
+}
+
+impl Landmarks {
+    /// Coordinates of all rows. Each non-landmark row is placed at its nearest landmark (in input space),
+    /// shifted towards the second nearest one by a fraction of the way depending on how close they are.
+    /// Averaging over more landmarks would put rows between fragments of a cluster that ended up apart.
+    /// This is pure interpolation: placed rows exert no forces and are not simulated afterwards.
+    pub fn place(&self, landmark_coords: ArrayView2<'_, f64>) -> Arr2 {
+        let n = self.records.len();
+        let mut landmark_of = vec![None; n];
+        for (a, &j) in self.indices.iter().enumerate() {
+            landmark_of[j] = Some(a);
+        }
+
+        let mut coords = Arr2::zeros((n, landmark_coords.ncols()));
+        for (j, &landmark) in landmark_of.iter().enumerate() {
+            if let Some(a) = landmark {
+                coords.row_mut(j).assign(&landmark_coords.row(a));
+                continue;
+            }
+            // (distance, landmark number) of the two nearest landmarks
+            let mut nearest = [(f64::INFINITY, 0); 2];
+            for (a, &l) in self.indices.iter().enumerate() {
+                let d = self.features.distance(j, l);
+                if d < nearest[0].0 {
+                    nearest = [(d, a), nearest[0]];
+                } else if d < nearest[1].0 {
+                    nearest[1] = (d, a);
+                }
+            }
+            let [(d1, a1), (d2, a2)] = nearest;
+            let mut row = coords.row_mut(j);
+            row.assign(&landmark_coords.row(a1));
+            if d2.is_finite() && d1 + d2 > 0.0 {
+                let t = SHIFT * d1 / (d1 + d2);
+                row.scaled_add(t, &landmark_coords.row(a2));
+                row.scaled_add(-t, &landmark_coords.row(a1));
+            }
+        }
+        coords
+    }
+}
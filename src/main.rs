@@ -1,232 +1,286 @@
-use std::io::Write;
-
-use interpolation::lerp;
-use rand::{Rng, SeedableRng};
-use trimothy::TrimSlice;
+use rand::Rng;
 
+mod affinities;
 mod algorithm;
+mod compression;
+mod config;
+mod diagnostics;
+mod dialect;
+mod estimate;
+mod features;
 mod flags;
+mod graph;
+mod input;
+mod landmarks;
+mod matrix;
+mod metadata;
+mod multilevel;
+mod restarts;
+mod rng;
+mod sampler;
+mod schedule;
+mod springs;
+mod stability;
+mod text;
+mod trajectory;
 
 type Arr2 = ndarray::Array2<f64>;
 type Arr1 = ndarray::Array1<f64>;
 
 fn main() -> anyhow::Result<()> {
-    let opts = flags::Csvdimreduce::from_env_or_exit();
-    let f = opts.get_istream()?;
-    let mut f = opts.get_csv_reader().from_reader(f);
-
-    let mut records = Vec::<csv::ByteRecord>::with_capacity(1024);
-    let header: Option<csv::ByteRecord> = if f.has_headers() && !opts.no_output_header {
-        Some(f.byte_headers()?.clone())
-    } else {
-        None
-    };
-    for record in f.into_byte_records() {
-        let record = record?;
-        records.push(record);
+    let started = std::time::Instant::now();
+    let mut opts = config::from_env_or_exit()?;
+    if opts.dump_config {
+        print!("{}", config::dump(&opts)?);
+        return Ok(());
     }
+    let sidecar = metadata::sidecar_path(&opts)?;
+    metadata::check_replayed_input(&opts)?;
+    let f = opts.open_input()?;
+    let input = input::read_input(&opts, f)?;
 
-    let n_out_coords = opts.n_out_coords;
-    let n_rows = records.len();
-    let n_input_coords = opts.columns.0.len();
-    let mut coords = Arr2::zeros((n_rows, n_out_coords));
-    let mut forces = Arr2::zeros((n_rows, n_out_coords));
-    let mut inertias = Arr2::zeros((n_rows, n_out_coords));
-    let mut weights = Arr1::zeros(n_rows);
-
-    let mut inputvals = Arr2::zeros((n_rows, n_input_coords));
-    let mut affinities = Arr2::zeros((n_rows, n_rows));
-
-    for (j, record) in records.iter().enumerate() {
-        let mut ctr = 0;
-        let mut weight_debt = opts.weight;
-        for (i, field) in record.iter().enumerate() {
-            if opts.columns.0.contains(&(i + 1)) {
-                let field = field.trim();
-                let x: f64 = std::str::from_utf8(field)?.parse()?;
-                inputvals[(j, ctr)] = x;
-                ctr += 1;
-            }
-            if Some(i + 1) == weight_debt {
-                let field = field.trim();
-                let x: f64 = std::str::from_utf8(field)?.parse()?;
-                weights[j] = x;
-                weight_debt = None;
-            }
-        }
-        if ctr != opts.columns.0.len() {
-            anyhow::bail!("Field list contains invalid column numbers");
-        }
-        if weight_debt.is_some() {
-            anyhow::bail!("Weight column is not found");
+    let seeds = restarts::seeds(&opts)?;
+    let runs = restarts::run_all(&opts, &seeds, |seed| {
+        let (coords, mut stats) = match opts.precision.unwrap_or_default() {
+            flags::Precision::F64 => simulate::<f64>(&opts, &input, seed)?,
+            flags::Precision::F32 => simulate::<f32>(&opts, &input, seed)?,
+        };
+        if opts.restarts.unwrap_or(1) > 1 {
+            stats.trustworthiness = Some(restarts::trustworthiness(&input.affinities, coords.view()));
         }
+        Ok((coords, stats))
+    })?;
+    let (mut coords, stats): (Vec<Arr2>, Vec<metadata::RunStats>) = runs.into_iter().unzip();
+    let scores: Vec<f64> = stats.iter().filter_map(|x| x.trustworthiness).collect();
+    let best = restarts::best(&seeds, &scores);
+    if opts.stability.is_none() && !opts.all_restarts {
+        coords = vec![coords.swap_remove(best)];
     }
-    if opts.normalize {
-        algorithm::normalize(inputvals.view_mut());
+    let (records, skipped, mut coords) = match input.landmarks {
+        Some(ref l) => (&l.records, &l.skipped, coords.iter().map(|c| l.place(c.view())).collect()),
+        None => (&input.records, &input.skipped, coords),
+    };
+    let mut names = coord_names(opts.n_out_coords);
+    if opts.stability.is_some() {
+        coords = vec![stability::analyze(&opts, &coords)?];
+        names.extend(stability::column_names(&opts));
     }
-    //println!("{inputvals}");
-    algorithm::build_particle_affinities(
-        inputvals.view(),
-        affinities.view_mut(),
-        opts.same_particle_force.unwrap_or(0.2),
-    );
-    drop(inputvals);
-    let mut rng = rand::rngs::StdRng::seed_from_u64(opts.random_seed.unwrap_or(1));
-    for j in 0..n_rows {
+    let coords: Vec<_> = coords.iter().map(|c| c.view()).collect();
+
+    let f = opts.get_ostream()?;
+    let f = opts.get_csv_writer().from_writer(f);
+    save_csv(&input.header, &names, f, records, skipped, &coords, opts.all_restarts)?;
+
+    if let Some(path) = sidecar {
+        metadata::write(&path, &opts, &input, &stats, best, started.elapsed().as_secs_f64())?;
+    }
+    Ok(())
+}
+
+/// Run all phases of the simulation with state stored as `T`, starting from positions generated by `seed`.
+/// Returns final coordinates.
+fn simulate<T: algorithm::Real>(
+    opts: &flags::Csvdimreduce,
+    input: &input::Input,
+    seed: u64,
+) -> anyhow::Result<(Arr2, metadata::RunStats)> {
+    let input::Input {
+        header,
+        records,
+        skipped,
+        weights,
+        affinities,
+        ..
+    } = input;
+
+    let n_out_coords = opts.n_out_coords;
+    let n_rows = records.len();
+    let levels = multilevel::coarsen(opts, affinities, weights.as_ref());
+    let weights = weights
+        .as_ref()
+        .map_or_else(|| ndarray::Array1::<T>::ones(n_rows), |w| w.mapv(T::of));
+    // Particles of the coarsest level, which is simulated with all phases
+    let coarsest = levels.last();
+    let coarsest_affinities = coarsest.map(|l| affinities.select(opts, &l.representatives));
+    let coarsest_weights = coarsest.map(|l| l.weights.mapv(T::of));
+    let n_particles = coarsest.map_or(n_rows, |l| l.representatives.len());
+
+    let mut coords = ndarray::Array2::<T>::zeros((n_particles, n_out_coords));
+    let mut forces = ndarray::Array2::<T>::zeros((n_particles, n_out_coords));
+    let mut inertias = ndarray::Array2::<T>::zeros((n_particles, n_out_coords));
+
+    let mut rng = rng::with_seed(seed);
+    for j in 0..n_particles {
         for i in 0..n_out_coords {
-            coords[(j, i)] = rng.gen();
+            coords[(j, i)] = T::of(rng.gen());
         }
     }
-    if opts.weight.is_none() {
-        weights.fill(1.0);
-    }
 
-    let avgaff = algorithm::average_affinity(affinities.view());
+    let mut sampler = opts.batch_size.map(|k| sampler::Sampler::new(k, rng));
+    let avgaff = match sampler {
+        Some(ref mut s) => s.average_affinity(affinities),
+        None => affinities.average(),
+    };
 
-    let n_iters = opts.n_iters.unwrap_or(100);
-    let warnup_iters = opts.warmup_iterations.unwrap_or(n_iters/2);
-    let rate = opts.rate.unwrap_or(0.01);
-    let inertia_multiplier = opts.inertia_multiplier.unwrap_or(0.9);
+    let schedule = schedule::Schedule::new(opts)?;
+    let rate = opts.rate.unwrap_or(schedule::DEFAULT_RATE);
     let final_rate = opts.final_rate.unwrap_or(0.02 * rate);
-    let central_force_param = opts.central_force.unwrap_or(20.0);
-    let central_force = avgaff * central_force_param;
-    let squeeze_rampup_rate = opts.squeeze_rampup_rate.unwrap_or(rate * 0.2);
-    let squeeze_rampup_iters =
-        opts.squeeze_rampup_iters
-            .unwrap_or(if opts.retain_coords_from_squeezing.is_some() {
-                n_iters * 1
-            } else {
-                0
-            });
-    let squeeze_final_iters =
-        opts.squeeze_final_iters
-            .unwrap_or(if opts.retain_coords_from_squeezing.is_some() {
-                n_iters
-            } else {
-                0
-            });
-    let squeeze_final_force_param = opts
-        .squeeze_final_force
-        .unwrap_or(10.0 * central_force_param);
-    let squeeze_final_force = avgaff * squeeze_final_force_param;
-    let squeeze_final_initial_rate = opts
-        .squeeze_final_initial_rate
-        .unwrap_or(squeeze_rampup_rate);
 
     if opts.debug {
-        println!("params basic_iters={n_iters} warmup_iters={warnup_iters} \
-        base_rate={rate} inertia_multiplier={inertia_multiplier} final_rate={final_rate} central_force={central_force_param} \
-        squeeze_rampup_rate={squeeze_rampup_rate} squeeze_rampup_iters={squeeze_rampup_iters} squeeze_final_iters={squeeze_final_iters} \
-        squeeze_final_force={squeeze_final_force_param} squeeze_final_initial_rate={squeeze_final_initial_rate} avgaff={avgaff}");
+        println!("avgaff={avgaff}");
+        for phase in &schedule.phases {
+            println!("phase {phase:?}");
+        }
     }
 
-    let mut total_iter_count = 0usize;
+    let level_iters = opts.level_iters.unwrap_or(multilevel::DEFAULT_LEVEL_ITERS);
+    let total_iters = schedule.total_iterations() + levels.len() * level_iters;
+    let mut recorder =
+        trajectory::Recorder::new(opts, header, records, skipped, n_out_coords, total_iters)?;
+    if !levels.is_empty() {
+        recorder.particle_of_row = Some(multilevel::particle_of_row(&levels, levels.len() - 1));
+    }
 
-    let mut inc_iter_and_maybe_save = |cv: ndarray::ArrayView2<'_, f64>| {
-        if let Some(se) = opts.save_each_n_iters {
-            if total_iter_count % se == 0 {
-                let Ok(f) = opts
-                    .get_csv_writer()
-                    .from_path(format!("debug{:05}.csv", total_iter_count)) else { return };
-                let _ = save_csv(&header, n_out_coords, f, &records, cv);
-            }
-        }
-        total_iter_count += 1;
-    };
+    let springs = springs::Springs::new(
+        opts,
+        coarsest_affinities.as_ref().unwrap_or(affinities),
+        avgaff,
+    );
 
     //println!("{} {}", data, weights);
-    let mut tmp = Arr1::zeros(n_out_coords);
+    let mut tmp = ndarray::Array1::<T>::zeros(n_out_coords);
+    let mut affinity_row = ndarray::Array1::<T>::zeros(n_particles);
     let mut state = algorithm::State {
         coords: coords.view_mut(),
         forces: forces.view_mut(),
         inertias: inertias.view_mut(),
-        weights: weights.view(),
-        affinities: affinities.view(),
+        weights: coarsest_weights.as_ref().unwrap_or(&weights).view(),
+        affinities: coarsest_affinities.as_ref().unwrap_or(affinities),
         tmp: tmp.view_mut(),
+        affinity_row: affinity_row.view_mut(),
+        sampler,
+        springs,
         movement_scaler: 0.0,
     };
     let mut params = algorithm::Params {
         rate,
-        central_force,
+        central_force: 0.0,
         squeeze_from: n_out_coords,
-        squeeze_force: central_force,
-        squeeze_force2: squeeze_final_force,
-        inertia_multiplier,
+        squeeze_force: 0.0,
+        squeeze_force2: 0.0,
+        inertia_multiplier: schedule::DEFAULT_INERTIA_MULTIPLIER,
+        force_law: opts.force_law.unwrap_or_default(),
         debug: opts.debug,
     };
-    for q in 0..n_iters {
-        inc_iter_and_maybe_save(state.coords.view());
-
-        if q < warnup_iters {
-            let t = (q + 1) as f64 / n_iters as f64;
-            params.rate = lerp(&(rate * 0.1), &(rate), &t);
-        } else if squeeze_final_iters == 0 {
-            params.rate = lerp(
-                &(rate * rate),
-                &(final_rate * final_rate),
-                &((q + 1) as f64 / n_iters as f64),
-            )
-            .sqrt();
-        } else {
-            params.rate = rate;
+    for phase in &schedule.phases {
+        if phase.reset_inertia {
+            state.inertias.fill(T::zero());
         }
-
-        state.step(&mut params);
-    }
-    let coords_to_squeeze =
-        n_out_coords.saturating_sub(opts.retain_coords_from_squeezing.unwrap_or(n_out_coords));
-    for squeze_this_number_of_coords in 1..=coords_to_squeeze {
-        state.inertias.fill(0.0);
-        params.squeeze_from = n_out_coords - squeze_this_number_of_coords;
-        for q in 0..squeeze_rampup_iters {
-            inc_iter_and_maybe_save(state.coords.view());
-            params.squeeze_force = lerp(
-                &central_force.ln(),
-                &squeeze_final_force.ln(),
-                &((q + 1) as f64 / squeeze_rampup_iters as f64),
-            )
-            .exp();
-            params.rate = squeeze_rampup_rate;
+        phase.start(&mut params, avgaff);
+        for q in 0..phase.iterations {
+            recorder.record(&phase.name, state.coords.view())?;
+            phase.update(&mut params, q, avgaff);
             state.step(&mut params);
+            watchdog(&state, &params, recorder.total_iter_count, &phase.name)?;
         }
     }
-    params.squeeze_force = params.squeeze_force2;
-    params.rate = squeeze_final_initial_rate;
-    for q in 0..squeeze_final_iters {
-        inc_iter_and_maybe_save(state.coords.view());
-        state.step(&mut params);
-        params.rate = lerp(
-            &(squeeze_final_initial_rate * squeeze_final_initial_rate),
-            &(final_rate * final_rate),
-            &((q + 1) as f64 / squeeze_final_iters as f64),
-        )
-        .sqrt();
-    }
+    let sampler = state.sampler.take();
+    let springs = state.springs.take();
+    let movement_scaler = state.movement_scaler;
+    let (coords, movement_scaler) = multilevel::uncoarsen(
+        opts,
+        &levels,
+        affinities,
+        weights.view(),
+        coords,
+        &mut params,
+        sampler,
+        springs,
+        &mut recorder,
+        rate,
+        final_rate,
+        movement_scaler,
+        seed,
+    )?;
+    let phases = recorder.finish(coords.view())?;
 
-    let f = opts.get_ostream()?;
-    let f = opts.get_csv_writer().from_writer(f);
-    save_csv(&header, n_out_coords, f, &records, coords.view())?;
+    let stats = metadata::RunStats {
+        phases,
+        movement_scaler,
+        seed,
+        trustworthiness: None,
+    };
+    Ok((coords.mapv(Into::into), stats))
+}
 
+/// Stop the simulation once anything becomes `NaN`, instead of silently producing garbage
+fn watchdog<T: algorithm::Real>(
+    state: &algorithm::State<T>,
+    params: &algorithm::Params,
+    iterations_done: usize,
+    phase: &str,
+) -> anyhow::Result<()> {
+    if let Some((j, c)) = state.find_non_finite() {
+        anyhow::bail!(
+            "Coordinate {} of particle {j} became {} after {iterations_done} iterations ({phase} phase). \
+            rate={} central_force={} squeeze_force={} movement_scaler={}",
+            c + 1,
+            state.coords[(j, c)],
+            params.rate,
+            params.central_force,
+            params.squeeze_force,
+            state.movement_scaler,
+        );
+    }
     Ok(())
 }
 
+/// `coord1`, `coord2`, ...
+fn coord_names(n_out_coords: usize) -> Vec<String> {
+    (1..=n_out_coords).map(|i| format!("coord{}", i)).collect()
+}
+
+/// Write `records` with `runs` of values for columns `names` before them.
+/// Skipped records get empty values.
 fn save_csv<'a>(
     header: &Option<csv::ByteRecord>,
-    n_out_coords: usize,
-    mut f: csv::Writer<impl Write>,
-    records: &Vec<csv::ByteRecord>,
-    coords: ndarray::ArrayView2<'a, f64>,
+    names: &[String],
+    mut f: csv::Writer<compression::OutputStream>,
+    records: &[csv::ByteRecord],
+    skipped: &[(usize, csv::ByteRecord)],
+    runs: &[ndarray::ArrayView2<'a, f64>],
+    restart_column: bool,
 ) -> Result<(), anyhow::Error> {
     if let Some(h) = &header {
-        for i in 1..=n_out_coords {
-            f.write_field(format!("coord{}", i))?;
+        if restart_column {
+            f.write_field("restart")?;
+        }
+        for name in names {
+            f.write_field(name)?;
         }
         f.write_record(h)?;
     }
-    Ok(for (j, record) in records.iter().enumerate() {
-        for i in 0..n_out_coords {
-            f.write_field(format!("{:.4}", coords[(j, i)]))?;
+    for (r, coords) in runs.iter().enumerate() {
+        let mut skipped = skipped.iter().peekable();
+        for j in 0..=records.len() {
+            while let Some((_, record)) = skipped.next_if(|x| x.0 <= j) {
+                if restart_column {
+                    f.write_field((r + 1).to_string())?;
+                }
+                for _ in names {
+                    f.write_field("")?;
+                }
+                f.write_record(record)?;
+            }
+            let Some(record) = records.get(j) else { break };
+            if restart_column {
+                f.write_field((r + 1).to_string())?;
+            }
+            for i in 0..names.len() {
+                f.write_field(format!("{:.4}", coords[(j, i)]))?;
+            }
+            f.write_record(record)?;
         }
-        f.write_record(record)?;
-    })
+    }
+    compression::finish_csv(f)
 }
@@ -0,0 +1,262 @@
+use std::collections::HashMap;
+
+use crate::affinities::{Affinities, DistanceTransform};
+use crate::flags::{AffinityStorage, Csvdimreduce};
+use anyhow::Context;
+
+use crate::input::{read_records, Input, NumberFormat};
+use crate::{diagnostics, Arr2};
+use trimothy::TrimSlice;
+
+pub fn reject_table_options(opts: &Csvdimreduce) -> anyhow::Result<()> {
+    if opts.weight.is_some() {
+        anyhow::bail!("--weight is only supported for table input");
+    }
+    if opts.landmarks.is_some() {
+        anyhow::bail!("--landmarks is only supported for table input");
+    }
+    if opts.affinities == Some(AffinityStorage::OnTheFly) {
+        anyhow::bail!("--affinities on-the-fly is only supported for table input");
+    }
+    if opts.normalize {
+        anyhow::bail!("--normalize is only supported for table input");
+    }
+    if !opts.column_type.is_empty() {
+        anyhow::bail!("--column-type is only supported for table input");
+    }
+    if opts.on_error.is_some() {
+        anyhow::bail!("--on-error is only supported for table input");
+    }
+    Ok(())
+}
+
+pub fn parse_distance(field: &[u8], fmt: &NumberFormat) -> anyhow::Result<f64> {
+    let d = fmt.parse(field)?;
+    if !d.is_finite() || d < 0.0 {
+        anyhow::bail!("Distance {d} is not a finite non-negative number");
+    }
+    Ok(d)
+}
+
+/// Header and records to output for each matrix row, read from `--labels` file.
+fn read_labels(opts: &Csvdimreduce) -> anyhow::Result<Option<Input>> {
+    let Some(ref path) = opts.labels else {
+        return Ok(None);
+    };
+    let f = crate::compression::decompress(Box::new(std::fs::File::open(path)?))?;
+    let f = crate::dialect::strip_bom(f)?;
+    let (header, records) = read_records(opts, f)?;
+    Ok(Some(Input::labels(
+        header.filter(|_| !opts.no_output_header),
+        records,
+    )))
+}
+
+fn single_field(x: &[u8]) -> csv::ByteRecord {
+    let mut r = csv::ByteRecord::new();
+    r.push_field(x);
+    r
+}
+
+/// Fields of the selected columns, checking that they exist. `j` is 0-based data row index.
+pub fn select_fields<'a>(
+    opts: &Csvdimreduce,
+    j: usize,
+    record: &'a csv::ByteRecord,
+) -> anyhow::Result<Vec<&'a [u8]>> {
+    diagnostics::check_columns("Field list", opts.columns.0.iter().copied(), record, || {
+        diagnostics::record_location(j, record)
+    })?;
+    Ok(opts.columns.0.iter().map(|i| &record[i - 1]).collect())
+}
+
+/// Maps node names to particle indices, for inputs that list pairs of nodes
+pub struct Nodes {
+    ids: HashMap<Vec<u8>, usize>,
+    /// Output records, either node names or records of `--labels` file
+    output: Input,
+    from_labels: bool,
+}
+
+impl Nodes {
+    /// `header` is input file header, for deciding whether to output one
+    pub fn new(opts: &Csvdimreduce, header: Option<csv::ByteRecord>) -> anyhow::Result<Nodes> {
+        Ok(if let Some(labels) = read_labels(opts)? {
+            Nodes {
+                ids: HashMap::new(),
+                output: labels,
+                from_labels: true,
+            }
+        } else {
+            Nodes {
+                ids: HashMap::new(),
+                output: Input::labels(header.map(|_| single_field(b"label")), vec![]),
+                from_labels: false,
+            }
+        })
+    }
+
+    /// With `--labels`, node names are 0-based record numbers. Otherwise new nodes are created on demand.
+    pub fn resolve(&mut self, x: &[u8]) -> anyhow::Result<usize> {
+        if self.from_labels {
+            let j: usize = std::str::from_utf8(x.trim())?.parse()?;
+            if j >= self.output.records.len() {
+                anyhow::bail!("Node index {j} is out of range of the labels file");
+            }
+            Ok(j)
+        } else {
+            let records = &mut self.output.records;
+            Ok(*self.ids.entry(x.to_vec()).or_insert_with(|| {
+                records.push(single_field(x));
+                records.len() - 1
+            }))
+        }
+    }
+
+    pub fn into_input(self, opts: &Csvdimreduce) -> Input {
+        let mut input = self.output;
+        if opts.no_output_header {
+            input.header = None;
+        }
+        input
+    }
+}
+
+/// Square matrix of distances, where selected columns correspond to rows
+pub fn read_dense(opts: &Csvdimreduce, f: Box<dyn std::io::Read>) -> anyhow::Result<Input> {
+    reject_table_options(opts)?;
+    let (header, records) = read_records(opts, f)?;
+    let n = records.len();
+    if opts.columns.0.len() != n {
+        anyhow::bail!(
+            "Distance matrix has {n} rows, but {} columns are selected",
+            opts.columns.0.len()
+        );
+    }
+    // Parsed as `f64`s, then converted if needed
+    let temporary = if opts.affinity_bytes() == 8 { 0 } else { 1 };
+    crate::estimate::preflight(opts, n, temporary)?;
+    let same_particle_force = opts.same_particle_force();
+    let fmt = NumberFormat::new(opts);
+    // Distances until they are transformed into affinities
+    let mut affinities = Arr2::zeros((n, n));
+    for (j, record) in records.iter().enumerate() {
+        diagnostics::check_columns("Field list", opts.columns.0.iter().copied(), record, || {
+            diagnostics::record_location(j, record)
+        })?;
+        for (ctr, &i) in opts.columns.0.iter().enumerate() {
+            let d = parse_distance(&record[i - 1], &fmt).with_context(|| {
+                format!(
+                    "Failed to parse {}",
+                    diagnostics::field_location(header.as_ref(), j, record, i)
+                )
+            })?;
+            affinities[(j, ctr)] = d;
+        }
+    }
+
+    let mut input = if let Some(labels) = read_labels(opts)? {
+        if labels.records.len() != n {
+            anyhow::bail!(
+                "Labels file has {} records, but the matrix has {n} rows",
+                labels.records.len()
+            );
+        }
+        labels
+    } else if records.iter().all(|r| r.len() > n) {
+        // Use non-distance fields of the matrix itself, like a leading name column
+        let project = |r: &csv::ByteRecord| -> csv::ByteRecord {
+            r.iter()
+                .enumerate()
+                .filter(|(i, _)| !opts.columns.0.contains(&(i + 1)))
+                .map(|(_, x)| x)
+                .collect()
+        };
+        Input::labels(
+            header.as_ref().map(project),
+            records.iter().map(project).collect(),
+        )
+    } else if let Some(ref h) = header {
+        Input::labels(
+            Some(single_field(b"label")),
+            opts.columns
+                .0
+                .iter()
+                .map(|i| single_field(&h[i - 1]))
+                .collect(),
+        )
+    } else {
+        Input::labels(
+            None,
+            (0..n)
+                .map(|j| single_field(j.to_string().as_bytes()))
+                .collect(),
+        )
+    };
+    if opts.no_output_header {
+        input.header = None;
+    }
+    let transform = DistanceTransform::new(opts, n, |j, k| affinities[(j, k)]);
+    affinities.mapv_inplace(|d| transform.apply(d) + same_particle_force);
+    input.affinities = Affinities::dense(opts, affinities);
+    Ok(input)
+}
+
+/// Sparse `i,j,d` distances. Pairs absent in both directions get the maximum specified distance.
+pub fn read_triples(opts: &Csvdimreduce, f: Box<dyn std::io::Read>) -> anyhow::Result<Input> {
+    reject_table_options(opts)?;
+    if opts.columns.0.len() != 3 {
+        anyhow::bail!(
+            "Triples input mode requires exactly 3 columns: first node, second node, distance"
+        );
+    }
+    let (header, triple_records) = read_records(opts, f)?;
+
+    let fmt = NumberFormat::new(opts);
+    let mut nodes = Nodes::new(opts, header.clone())?;
+    let mut triples = Vec::with_capacity(triple_records.len());
+    for (j, record) in triple_records.iter().enumerate() {
+        let [a, b, d] = select_fields(opts, j, record)?[..] else {
+            unreachable!()
+        };
+        let at = || diagnostics::record_location(j, record);
+        let a = nodes.resolve(a).with_context(at)?;
+        let b = nodes.resolve(b).with_context(at)?;
+        let d = parse_distance(d, &fmt).with_context(|| {
+            let column = *opts.columns.0.last().unwrap();
+            format!(
+                "Failed to parse {}",
+                diagnostics::field_location(header.as_ref(), j, record, column)
+            )
+        })?;
+        triples.push((a, b, d));
+    }
+
+    let mut input = nodes.into_input(opts);
+    let n = input.records.len();
+    crate::estimate::preflight(opts, n, 1)?;
+    let mut distances = Arr2::from_elem((n, n), f64::NAN);
+    let mut max_distance = 0.0f64;
+    for (a, b, d) in triples {
+        distances[(a, b)] = d;
+        max_distance = max_distance.max(d);
+    }
+    let distance = |j, k| {
+        let d: f64 = distances[(j, k)];
+        let d = if d.is_nan() { distances[(k, j)] } else { d };
+        if d.is_nan() {
+            max_distance
+        } else {
+            d
+        }
+    };
+    let transform = DistanceTransform::new(opts, n, distance);
+    let same_particle_force = opts.same_particle_force();
+    input.affinities = Affinities::build(opts, n, |j, k| {
+        if j == k {
+            return same_particle_force;
+        }
+        transform.apply(distance(j, k)) + same_particle_force
+    });
+    Ok(input)
+}
@@ -0,0 +1,219 @@
+//! `--metadata` sidecar recording how the output was produced, and `--replay` of such a run
+
+use std::ffi::OsString;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+use crate::flags::{Csvdimreduce, InputMode};
+use crate::input::Input;
+
+/// Recorded options that name files written by the run
+const NOT_REPLAYED: &[&str] = &["output", "metadata", "trajectory", "save-each-n-iters"];
+
+/// What happened during the simulation, besides the resulting coordinates
+pub struct RunStats {
+    /// Names of phases and numbers of iterations done in them, in order
+    pub phases: Vec<(String, usize)>,
+    pub movement_scaler: f64,
+    pub seed: u64,
+    /// Only computed if there are several `--restarts`
+    pub trustworthiness: Option<f64>,
+}
+
+/// `-o` path with `.json` appended, if `--metadata` is specified
+pub fn sidecar_path(opts: &Csvdimreduce) -> anyhow::Result<Option<PathBuf>> {
+    if !opts.metadata {
+        return Ok(None);
+    }
+    let Some(ref output) = opts.output else {
+        anyhow::bail!("--metadata requires -o, as the file is written next to the output");
+    };
+    let mut path = output.clone().into_os_string();
+    path.push(".json");
+    Ok(Some(path.into()))
+}
+
+pub fn write(
+    path: &Path,
+    opts: &Csvdimreduce,
+    input: &Input,
+    runs: &[RunStats],
+    best: usize,
+    wall_seconds: f64,
+) -> anyhow::Result<()> {
+    let stats = &runs[best];
+    let input_path = absolute(opts.path.as_deref())?;
+    let schedule_path = absolute(opts.schedule.as_deref())?;
+    let labels_path = absolute(opts.labels.as_deref())?;
+    let mut parameters = crate::config::resolved(opts);
+    for (key, path) in [("schedule", &schedule_path), ("labels", &labels_path)] {
+        if let Some(p) = path {
+            parameters.insert(key.to_owned(), p.display().to_string().into());
+        }
+    }
+    // Other input modes have no per-column header
+    let header = match opts.input_mode.unwrap_or_default() {
+        InputMode::Table => input.header.as_ref(),
+        _ => None,
+    };
+    let columns: Vec<Value> = opts
+        .columns
+        .0
+        .iter()
+        .map(|&i| {
+            let name = header
+                .and_then(|h| h.get(i - 1))
+                .map(|x| String::from_utf8_lossy(x));
+            json!({ "index": i, "name": name })
+        })
+        .collect();
+    let normalization: Vec<Value> = input
+        .normalization
+        .iter()
+        .map(|x| json!({ "column": x.column, "mean": x.mean, "scale": x.scale }))
+        .collect();
+    let phases: Vec<Value> = stats
+        .phases
+        .iter()
+        .map(|(name, n)| json!({ "name": name, "iterations": n }))
+        .collect();
+    let mut metadata = json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "arguments": {
+            "columns": opts.columns.to_string(),
+            "n_out_coords": opts.n_out_coords,
+            "path": input_path,
+        },
+        "parameters": parameters,
+        "seed": stats.seed,
+        "input": file_record(input_path.as_deref())?,
+        "schedule": file_record(schedule_path.as_deref())?,
+        "labels": file_record(labels_path.as_deref())?,
+        "columns": columns,
+        "normalization": normalization,
+        "phases": phases,
+        "movement_scaler": stats.movement_scaler,
+        "wall_seconds": wall_seconds,
+    });
+    if opts.stability.is_some() {
+        let seeds: Vec<u64> = runs.iter().map(|x| x.seed).collect();
+        metadata["stability_seeds"] = seeds.into();
+    } else if runs.len() > 1 {
+        let restarts: Vec<Value> = runs
+            .iter()
+            .map(|x| json!({ "seed": x.seed, "trustworthiness": x.trustworthiness }))
+            .collect();
+        metadata["restarts"] = restarts.into();
+        metadata["best_restart"] = (best + 1).into();
+    }
+    let f = std::fs::File::create(path)
+        .with_context(|| format!("Failed to create metadata file {}", path.display()))?;
+    let mut f = std::io::BufWriter::new(f);
+    serde_json::to_writer_pretty(&mut f, &metadata)?;
+    std::io::Write::write_all(&mut f, b"\n")?;
+    Ok(())
+}
+
+/// Positional arguments and options of the run recorded in `--replay` file
+pub fn load_replay(path: &Path) -> anyhow::Result<(Vec<OsString>, toml::Table)> {
+    let metadata = load(path)?;
+    let arguments = &metadata["arguments"];
+    let (Some(columns), Some(n_out_coords)) = (
+        arguments["columns"].as_str(),
+        arguments["n_out_coords"].as_u64(),
+    ) else {
+        anyhow::bail!("{} has no recorded arguments", path.display());
+    };
+    let mut positional: Vec<OsString> = vec![columns.into(), n_out_coords.to_string().into()];
+    if let Some(input) = arguments["path"].as_str() {
+        positional.push(input.into());
+    }
+    let mut parameters: toml::Table = serde_json::from_value(metadata["parameters"].clone())
+        .with_context(|| format!("Invalid parameters in {}", path.display()))?;
+    // Replaying should not overwrite the recorded output or this file
+    for key in NOT_REPLAYED {
+        parameters.remove(*key);
+    }
+    // Skipped rows are the same, but the quarantine file is not written again
+    if let Some(toml::Value::String(on_error)) = parameters.get_mut("on-error") {
+        if on_error.starts_with("quarantine") {
+            *on_error = "skip".to_owned();
+        }
+    }
+    Ok((positional, parameters))
+}
+
+/// Warn if the input, `--schedule` or `--labels` file of the `--replay`ed run has changed since it was recorded
+pub fn check_replayed_input(opts: &Csvdimreduce) -> anyhow::Result<()> {
+    let Some(ref replay) = opts.replay else {
+        return Ok(());
+    };
+    let recorded = load(replay)?;
+    for (key, path) in [
+        ("input", &opts.path),
+        ("schedule", &opts.schedule),
+        ("labels", &opts.labels),
+    ] {
+        let Some(path) = path else { continue };
+        let recorded = &recorded[key];
+        let (size, sha256) = digest(path)?;
+        if recorded["size"].as_u64() != Some(size) || recorded["sha256"].as_str() != Some(&sha256) {
+            eprintln!(
+                "Warning: {} differs from the {key} file of the recorded run, results may differ",
+                path.display()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Absolute path, so that the run can be replayed from another directory
+fn absolute(path: Option<&Path>) -> anyhow::Result<Option<PathBuf>> {
+    path.map(|p| {
+        std::fs::canonicalize(p).with_context(|| format!("Failed to resolve path {}", p.display()))
+    })
+    .transpose()
+}
+
+/// Path, size and hash of a file read by the run
+fn file_record(path: Option<&Path>) -> anyhow::Result<Value> {
+    let Some(path) = path else {
+        return Ok(Value::Null);
+    };
+    let (size, sha256) = digest(path)?;
+    Ok(json!({ "path": path, "size": size, "sha256": sha256 }))
+}
+
+fn load(path: &Path) -> anyhow::Result<Value> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read metadata file {}", path.display()))?;
+    serde_json::from_str(&text)
+        .with_context(|| format!("Failed to parse metadata file {}", path.display()))
+}
+
+/// Size and hex-encoded SHA-256 of a file
+fn digest(path: &Path) -> anyhow::Result<(u64, String)> {
+    let mut f =
+        std::fs::File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; 1 << 16];
+    let mut size = 0;
+    loop {
+        let n = f.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+        size += n as u64;
+    }
+    let sha256 = hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect();
+    Ok((size, sha256))
+}
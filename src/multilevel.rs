@@ -0,0 +1,205 @@
+//! Coarse-to-fine simulation: nearest particles are merged into heavier ones and the coarsest level is laid out first
+
+use interpolation::lerp;
+use ndarray::{Array2, ArrayView1};
+use rand::Rng;
+
+use crate::affinities::Affinities;
+use crate::algorithm::{Params, Real, State};
+use crate::flags::Csvdimreduce;
+use crate::sampler::Sampler;
+use crate::springs::Springs;
+use crate::trajectory::Recorder;
+use crate::Arr1;
+
+pub const DEFAULT_COARSEST: usize = 100;
+pub const DEFAULT_LEVEL_ITERS: usize = 10;
+/// Refinement of each level starts with this fraction of `--rate` and slows down to `--final-rate`
+const LEVEL_RATE: f64 = 0.05;
+/// Split particles start up to this far from the position of the merged one
+const JITTER: f64 = 0.001;
+
+pub struct Level {
+    /// Row whose affinities each particle uses
+    pub representatives: Vec<usize>,
+    pub weights: Arr1,
+    /// Particle of this level containing each particle of the finer one
+    pub parent: Vec<usize>,
+}
+
+/// Levels from the finest to the coarsest, not including the input itself.
+/// Empty unless `--multilevel` is specified.
+pub fn coarsen(opts: &Csvdimreduce, affinities: &Affinities, weights: Option<&Arr1>) -> Vec<Level> {
+    let mut levels: Vec<Level> = vec![];
+    if !opts.multilevel {
+        return levels;
+    }
+    let coarsest = opts.coarsest.unwrap_or(DEFAULT_COARSEST);
+    let n = affinities.len();
+    let rows: Vec<usize> = (0..n).collect();
+    let ones = Arr1::ones(n);
+    let weights = weights.unwrap_or(&ones);
+    loop {
+        let (representatives, weights) = match levels.last() {
+            Some(l) => (&l.representatives, &l.weights),
+            None => (&rows, weights),
+        };
+        let m = representatives.len();
+        if m <= coarsest.max(1) {
+            break;
+        }
+        levels.push(merge_nearest(affinities, representatives, weights.view()));
+    }
+    if opts.debug {
+        let sizes: Vec<usize> = levels.iter().map(|l| l.representatives.len()).collect();
+        println!("levels {sizes:?}");
+    }
+    levels
+}
+
+/// Merge each particle not merged yet with its nearest neighbour among ones not merged yet either,
+/// which halves the number of particles. The merged particle keeps affinities of the heavier one
+/// and sum of their weights.
+fn merge_nearest(
+    affinities: &Affinities,
+    representatives: &[usize],
+    weights: ArrayView1<'_, f64>,
+) -> Level {
+    let m = representatives.len();
+    let mut parent = vec![usize::MAX; m];
+    let mut merged_representatives = Vec::with_capacity(m / 2 + 1);
+    let mut merged_weights = Vec::with_capacity(m / 2 + 1);
+    for j in 0..m {
+        if parent[j] != usize::MAX {
+            continue;
+        }
+        // Smallest affinity means the weakest repulsion, i.e. the most similar row
+        let nearest = (0..m)
+            .filter(|&k| k != j && parent[k] == usize::MAX)
+            .map(|k| (affinities.get(representatives[j], representatives[k]), k))
+            .min_by(|a, b| a.0.total_cmp(&b.0))
+            .map(|x| x.1);
+        let p = merged_representatives.len();
+        parent[j] = p;
+        let mut representative = j;
+        let mut weight = weights[j];
+        if let Some(k) = nearest {
+            parent[k] = p;
+            if weights[k] > weights[j] {
+                representative = k;
+            }
+            weight += weights[k];
+        }
+        merged_representatives.push(representatives[representative]);
+        merged_weights.push(weight);
+    }
+    Level {
+        representatives: merged_representatives,
+        weights: Arr1::from(merged_weights),
+        parent,
+    }
+}
+
+/// Work of simulating `n` particles with `total_iters` iterations of the coarsest level,
+/// in iterations over all of them. Coarsening itself costs about as much as one iteration per level.
+pub fn equivalent_iterations(opts: &Csvdimreduce, n: usize, total_iters: usize) -> usize {
+    let coarsest = opts.coarsest.unwrap_or(DEFAULT_COARSEST);
+    if !opts.multilevel || n <= coarsest {
+        return total_iters;
+    }
+    let level_iters = opts.level_iters.unwrap_or(DEFAULT_LEVEL_ITERS) as f64;
+    // Sum of squared sizes of all levels relative to the finest one, as each is half of the previous
+    let levels = 4.0 / 3.0;
+    let coarse = (coarsest as f64 / n as f64).powi(2);
+    (levels * (level_iters + 1.0) + total_iters as f64 * coarse).ceil() as usize
+}
+
+/// Particle of `levels[i]` containing each row
+pub fn particle_of_row(levels: &[Level], i: usize) -> Vec<usize> {
+    let mut particles: Vec<usize> = (0..levels[0].parent.len()).collect();
+    for level in &levels[..=i] {
+        for p in particles.iter_mut() {
+            *p = level.parent[*p];
+        }
+    }
+    particles
+}
+
+/// Split particles back level by level, starting from `coords` of the coarsest one,
+/// and refine their positions after each split. Returns coordinates of all rows
+/// and `movement_scaler` of the last simulated level, which is the coarsest one's if there are no levels.
+#[allow(clippy::too_many_arguments)]
+pub fn uncoarsen<T: Real>(
+    opts: &Csvdimreduce,
+    levels: &[Level],
+    affinities: &Affinities,
+    weights: ArrayView1<'_, T>,
+    mut coords: Array2<T>,
+    params: &mut Params,
+    mut sampler: Option<Sampler>,
+    springs: Option<Springs>,
+    recorder: &mut Recorder<'_>,
+    rate: f64,
+    final_rate: f64,
+    mut movement_scaler: f64,
+    seed: u64,
+) -> anyhow::Result<(Array2<T>, f64)> {
+    let level_iters = opts.level_iters.unwrap_or(DEFAULT_LEVEL_ITERS);
+    let mut rng = crate::rng::with_seed(seed);
+    for i in (0..levels.len()).rev() {
+        let level_affinities;
+        let (affinities, weights) = if i > 0 {
+            let finer = &levels[i - 1];
+            level_affinities = affinities.select(opts, &finer.representatives);
+            (&level_affinities, finer.weights.mapv(T::of))
+        } else {
+            (affinities, weights.to_owned())
+        };
+        recorder.particle_of_row = (i > 0).then(|| particle_of_row(levels, i - 1));
+
+        let parent = &levels[i].parent;
+        let (n, d) = (parent.len(), coords.ncols());
+        let mut fine = Array2::<T>::zeros((n, d));
+        for ((j, c), x) in fine.indexed_iter_mut() {
+            // Squeezed coordinates are kept as they are
+            let jitter = if c < params.squeeze_from {
+                T::of(JITTER * (rng.gen::<f64>() - 0.5))
+            } else {
+                T::zero()
+            };
+            *x = num_traits::clamp(coords[(parent[j], c)] + jitter, T::zero(), T::one());
+        }
+        let mut forces = Array2::<T>::zeros((n, d));
+        let mut inertias = Array2::<T>::zeros((n, d));
+        let mut tmp = ndarray::Array1::<T>::zeros(d);
+        let mut affinity_row = ndarray::Array1::<T>::zeros(n);
+        let mut state = State {
+            coords: fine.view_mut(),
+            forces: forces.view_mut(),
+            inertias: inertias.view_mut(),
+            weights: weights.view(),
+            affinities,
+            tmp: tmp.view_mut(),
+            affinity_row: affinity_row.view_mut(),
+            sampler: sampler.take(),
+            springs: springs.as_ref().map(|s| s.reconnect(affinities)),
+            movement_scaler: 0.0,
+        };
+        let start_rate = LEVEL_RATE * rate;
+        for q in 0..level_iters {
+            recorder.record("refine", state.coords.view())?;
+            params.rate = lerp(
+                &(start_rate * start_rate),
+                &(final_rate * final_rate),
+                &(q as f64 / level_iters as f64),
+            )
+            .sqrt();
+            state.step(params);
+            crate::watchdog(&state, params, recorder.total_iter_count, "refine")?;
+        }
+        sampler = state.sampler.take();
+        movement_scaler = state.movement_scaler;
+        coords = fine;
+    }
+    Ok((coords, movement_scaler))
+}
@@ -0,0 +1,144 @@
+//! Independent simulations from different seeds, run in parallel: `--restarts` scored to pick the best one
+//! and `--stability` runs compared with each other
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use ndarray::ArrayView2;
+
+use crate::affinities::Affinities;
+use crate::flags::Csvdimreduce;
+
+/// Size of the neighbourhoods compared by [`trustworthiness`]
+const SCORE_NEIGHBORS: usize = 10;
+/// Trustworthiness is computed for at most this many evenly spaced rows, as each one costs a sort of all rows
+const SCORED_ROWS: usize = 1000;
+
+/// Number of simulations, from `--restarts` or `--stability`
+pub fn count(opts: &Csvdimreduce) -> usize {
+    opts.stability.or(opts.restarts).unwrap_or(1)
+}
+
+/// Seeds of all simulations: `--random-seed`, then each next number
+pub fn seeds(opts: &Csvdimreduce) -> anyhow::Result<Vec<u64>> {
+    if opts.restarts == Some(0) {
+        anyhow::bail!("--restarts must be positive");
+    }
+    if opts.threads == Some(0) {
+        anyhow::bail!("--threads must be positive");
+    }
+    if let Some(runs) = opts.stability {
+        if opts.restarts.is_some_and(|x| x > 1) || opts.all_restarts {
+            anyhow::bail!("--stability cannot be combined with --restarts or --all-restarts");
+        }
+        if runs < 2 {
+            anyhow::bail!("--stability needs at least 2 runs");
+        }
+    } else if opts.stability_neighbors.is_some() {
+        anyhow::bail!("--stability-neighbors requires --stability");
+    }
+    let runs = count(opts);
+    if runs > 1 && (opts.trajectory.is_some() || opts.save_each_n_iters.is_some()) {
+        anyhow::bail!(
+            "--trajectory and --save-each-n-iters cannot be combined with --restarts or --stability"
+        );
+    }
+    let seed = crate::rng::seed(opts);
+    Ok((0..runs as u64).map(|i| seed.wrapping_add(i)).collect())
+}
+
+/// Number of simulations running at the same time
+pub fn threads(opts: &Csvdimreduce) -> usize {
+    let cpus = std::thread::available_parallelism().map_or(1, |x| x.get());
+    opts.threads.unwrap_or(cpus).clamp(1, count(opts).max(1))
+}
+
+/// Call `f` for each seed, up to [`threads`] at a time. Results are in order of seeds,
+/// the first error (also in that order) is returned.
+pub fn run_all<R: Send>(
+    opts: &Csvdimreduce,
+    seeds: &[u64],
+    f: impl Fn(u64) -> anyhow::Result<R> + Sync,
+) -> anyhow::Result<Vec<R>> {
+    let threads = threads(opts);
+    if threads == 1 {
+        return seeds.iter().map(|&s| f(s)).collect();
+    }
+    let next = AtomicUsize::new(0);
+    let results: Vec<Mutex<Option<anyhow::Result<R>>>> =
+        seeds.iter().map(|_| Mutex::new(None)).collect();
+    std::thread::scope(|scope| {
+        for _ in 0..threads {
+            scope.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                let Some(&seed) = seeds.get(i) else { break };
+                *results[i].lock().unwrap() = Some(f(seed));
+            });
+        }
+    });
+    results
+        .into_iter()
+        .map(|r| r.into_inner().unwrap().unwrap())
+        .collect()
+}
+
+/// Trustworthiness of the layout: 1 if nearest neighbours of each row in `coords` are also its nearest ones
+/// in input space (lowest affinities), lower the farther away in input space they are. Ties are broken by row index.
+pub fn trustworthiness(affinities: &Affinities, coords: ArrayView2<'_, f64>) -> f64 {
+    let n = coords.nrows();
+    let k = SCORE_NEIGHBORS.min(n.saturating_sub(2) / 3);
+    if k == 0 {
+        return 1.0;
+    }
+    let m = n.min(SCORED_ROWS);
+    let mut rank = vec![0; n];
+    let mut order: Vec<usize> = Vec::with_capacity(n);
+    let mut penalty = 0.0;
+    for j in (0..m).map(|i| i * n / m) {
+        let others = (0..n).filter(|&v| v != j);
+        order.clear();
+        order.extend(others.clone());
+        let input: Vec<f64> = (0..n).map(|v| affinities.get(j, v)).collect();
+        order.sort_by(|&a, &b| input[a].total_cmp(&input[b]).then(a.cmp(&b)));
+        for (r, &v) in order.iter().enumerate() {
+            rank[v] = r + 1;
+        }
+        let output: Vec<f64> = (0..n)
+            .map(|v| {
+                let d = &coords.row(v) - &coords.row(j);
+                d.dot(&d)
+            })
+            .collect();
+        order.clear();
+        order.extend(others);
+        order.select_nth_unstable_by(k - 1, |&a, &b| {
+            output[a].total_cmp(&output[b]).then(a.cmp(&b))
+        });
+        penalty += order[..k]
+            .iter()
+            .map(|&v| rank[v].saturating_sub(k) as f64)
+            .sum::<f64>();
+    }
+    1.0 - 2.0 * penalty / (m * k * (2 * n - 3 * k - 1)) as f64
+}
+
+/// Index of the restart with the highest score, the first one among equal ones.
+/// Prints all scores to stderr if there are several restarts.
+pub fn best(seeds: &[u64], scores: &[f64]) -> usize {
+    let mut best = 0;
+    for (i, &s) in scores.iter().enumerate() {
+        if s > scores[best] {
+            best = i;
+        }
+    }
+    if scores.len() > 1 {
+        for (i, (seed, score)) in seeds.iter().zip(scores).enumerate() {
+            let mark = if i == best { " (best)" } else { "" };
+            eprintln!(
+                "Restart {}: seed {seed}, trustworthiness {score:.4}{mark}",
+                i + 1
+            );
+        }
+    }
+    best
+}
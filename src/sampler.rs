@@ -0,0 +1,154 @@
+//! Stochastic estimation of repulsion from a few sampled partners instead of all particles
+
+use ndarray::{ArrayView1, ArrayView2, ArrayViewMut1, ArrayViewMut2};
+use rand::Rng as _;
+
+use crate::affinities::Affinities;
+use crate::algorithm::{Real, MIN_SQNORM};
+use crate::flags::ForceLaw;
+use crate::rng::Rng;
+
+/// Number of uniformly drawn candidates per sampled partner
+pub const OVERSAMPLING: usize = 4;
+/// Force components above this quantile are considered sampling noise
+const CLIP_QUANTILE: f64 = 0.9;
+
+pub struct Sampler {
+    /// Number of partners per particle per iteration
+    pub batch_size: usize,
+    pub rng: Rng,
+    /// Candidate partners with cumulative sums of their affinities
+    candidates: Vec<(usize, f64)>,
+    /// Dimension is like in coordinates
+    direction: Vec<f64>,
+    repulsion: Vec<f64>,
+    /// Absolute values of force components, for finding their quantile
+    magnitudes: Vec<f64>,
+}
+
+impl Sampler {
+    pub fn new(batch_size: usize, rng: Rng) -> Sampler {
+        Sampler {
+            batch_size,
+            rng,
+            candidates: Vec::with_capacity(batch_size * OVERSAMPLING),
+            direction: vec![],
+            repulsion: vec![],
+            magnitudes: vec![],
+        }
+    }
+
+    /// Whether the batch is no smaller than all `n - 1` other particles, so exact forces are no more expensive
+    pub fn covers_all(&self, n: usize) -> bool {
+        self.batch_size + 1 >= n
+    }
+
+    /// Random particle other than `j`
+    fn partner(&mut self, n: usize, j: usize) -> usize {
+        let p = crate::rng::index(&mut self.rng, 0..n - 1);
+        if p >= j {
+            p + 1
+        } else {
+            p
+        }
+    }
+
+    /// Add estimated repulsion of particle `j` from all others to `forces`.
+    ///
+    /// Candidates are drawn uniformly, then partners are picked among them with probability
+    /// proportional to affinity. Each pick stands for `(n - 1) / batch_size` particles
+    /// with the average affinity of the candidates, so the expected sum is close to the exact one.
+    pub fn add_repulsion<T: Real>(
+        &mut self,
+        j: usize,
+        coords: ArrayView2<'_, T>,
+        weights: ArrayView1<'_, T>,
+        affinities: &Affinities,
+        force_law: ForceLaw,
+        mut forces: ArrayViewMut1<'_, T>,
+    ) {
+        let n = coords.nrows();
+        if n < 2 || self.batch_size == 0 {
+            return;
+        }
+        self.candidates.clear();
+        let mut total = 0.0;
+        for _ in 0..self.batch_size * OVERSAMPLING {
+            let p = self.partner(n, j);
+            total += affinities.get(j, p);
+            self.candidates.push((p, total));
+        }
+        if total <= 0.0 {
+            return;
+        }
+        let affinity =
+            total / self.candidates.len() as f64 * (n - 1) as f64 / self.batch_size as f64;
+
+        // Plain slices are much faster than ndarray indexing for few coordinates
+        let d = coords.ncols();
+        let coords = coords.as_standard_layout();
+        let coords = coords.as_slice().unwrap();
+        let my_coords = &coords[j * d..(j + 1) * d];
+        let my_weight: f64 = weights[j].into();
+        self.direction.resize(d, 0.0);
+        self.repulsion.clear();
+        self.repulsion.resize(d, 0.0);
+        for _ in 0..self.batch_size {
+            let r = self.rng.gen_range(0.0..total);
+            let i = self.candidates.partition_point(|c| c.1 <= r);
+            let p = self.candidates[i.min(self.candidates.len() - 1)].0;
+
+            let their_coords = &coords[p * d..(p + 1) * d];
+            let mut sqnorm = 0.0;
+            for c in 0..d {
+                let x = my_coords[c].into() - their_coords[c].into();
+                self.direction[c] = x;
+                sqnorm += x * x;
+            }
+            let sqnorm = sqnorm.max(MIN_SQNORM);
+            let their_weight: f64 = weights[p].into();
+            // Also divided by norm to make direction a unit vector
+            let repelling_force =
+                force_law.repulsion(affinity, sqnorm) * their_weight / my_weight / sqnorm.sqrt();
+            for c in 0..d {
+                self.repulsion[c] += repelling_force * self.direction[c];
+            }
+        }
+        for (f, &x) in forces.iter_mut().zip(&self.repulsion) {
+            *f += T::of(x);
+        }
+    }
+
+    /// Limit components of estimated repulsion to a high quantile of their absolute values.
+    /// Rare partners sampled very close to a particle would otherwise dominate the movement scale.
+    pub fn clip_outliers<T: Real>(&mut self, mut repulsion: ArrayViewMut2<'_, T>) {
+        self.magnitudes.clear();
+        self.magnitudes
+            .extend(repulsion.iter().map(|&f| f.into().abs()));
+        if self.magnitudes.is_empty() {
+            return;
+        }
+        let i = ((self.magnitudes.len() - 1) as f64 * CLIP_QUANTILE) as usize;
+        let (_, &mut limit, _) = self.magnitudes.select_nth_unstable_by(i, f64::total_cmp);
+        let (lo, hi) = (T::of(-limit), T::of(limit));
+        repulsion.mapv_inplace(|f| num_traits::clamp(f, lo, hi));
+    }
+
+    /// Average element of the affinity matrix, estimated from random pairs
+    pub fn average_affinity(&mut self, affinities: &Affinities) -> f64 {
+        let n = affinities.len();
+        if n < 2 || self.covers_all(n) {
+            return affinities.average();
+        }
+        let diagonal: f64 = (0..n).map(|j| affinities.get(j, j)).sum();
+        let samples = n * self.batch_size.max(1) * OVERSAMPLING;
+        let mut off_diagonal = 0.0;
+        for _ in 0..samples {
+            let j = crate::rng::index(&mut self.rng, 0..n);
+            let p = self.partner(n, j);
+            off_diagonal += affinities.get(j, p);
+        }
+        let off_diagonal = off_diagonal / samples as f64 * (n * (n - 1)) as f64;
+        (diagonal + off_diagonal) / (n as f64 * n as f64)
+    }
+}
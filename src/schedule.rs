@@ -0,0 +1,596 @@
+//! Ordered list of simulation phases, either built from command line flags or read from `--schedule` file
+
+use std::path::Path;
+
+use interpolation::lerp;
+use serde::Deserialize;
+
+use crate::algorithm::Params;
+use crate::flags::Csvdimreduce;
+
+pub const DEFAULT_RATE: f64 = 0.01;
+pub const DEFAULT_INERTIA_MULTIPLIER: f64 = 0.9;
+pub const DEFAULT_CENTRAL_FORCE: f64 = 20.0;
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Schedule {
+    #[serde(rename = "phase", alias = "phases")]
+    pub phases: Vec<Phase>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Phase {
+    /// Shown in `--debug` output, trajectories and error messages. Default is `phase1`, `phase2`, ...
+    #[serde(default)]
+    pub name: String,
+    pub iterations: usize,
+    pub rate: Curve,
+    /// Relative to average affinity, like `--central-force`, which is the default
+    pub central_force: Option<Curve>,
+    /// Number of leading coordinates that are not squeezed, like `-S`. Default is all of them.
+    pub retain_coords: Option<usize>,
+    /// Central force for the first squeezed coordinate. Default is `squeezed_force`.
+    pub squeeze_force: Option<Curve>,
+    /// Central force for other squeezed coordinates, like `--squeeze-final-force`, which is the default
+    pub squeezed_force: Option<f64>,
+    /// Like `--inertia-multiplier`, which is the default
+    pub inertia: Option<f64>,
+    /// Forget accumulated movement at the start of the phase
+    #[serde(default)]
+    pub reset_inertia: bool,
+}
+
+/// Value changing over a phase: a number, a segment or a list of segments
+#[derive(Debug, Deserialize)]
+#[serde(from = "CurveSpec")]
+pub struct Curve(Vec<Segment>);
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum CurveSpec {
+    Constant(f64),
+    Segment(Segment),
+    Segments(Vec<Segment>),
+}
+
+/// Part of a curve. All segments of a curve interpolate over progress through the whole phase,
+/// each one applies to iterations before its `until` that are not covered by previous ones.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Segment {
+    pub shape: Shape,
+    pub from: f64,
+    /// Value at the end of the phase. Default is `from`.
+    pub to: Option<f64>,
+    /// Number of iterations from the start of the phase. Default is all remaining ones.
+    pub until: Option<usize>,
+    /// Take value for iteration at its start instead of its end, so the first one uses `from`
+    #[serde(default)]
+    pub at_start: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Shape {
+    Constant,
+    Linear,
+    /// Linear in logarithm of the value
+    Exponential,
+    /// Half of a cosine wave, slow at both ends
+    Cosine,
+    /// Linear in square of the value
+    SqrtLerp,
+}
+
+impl From<CurveSpec> for Curve {
+    fn from(spec: CurveSpec) -> Curve {
+        match spec {
+            CurveSpec::Constant(x) => Curve::constant(x),
+            CurveSpec::Segment(s) => Curve(vec![s]),
+            CurveSpec::Segments(v) => Curve(v),
+        }
+    }
+}
+
+impl Segment {
+    fn new(shape: Shape, from: f64, to: f64) -> Segment {
+        Segment {
+            shape,
+            from,
+            to: Some(to),
+            until: None,
+            at_start: false,
+        }
+    }
+}
+
+impl Curve {
+    pub fn constant(x: f64) -> Curve {
+        Curve(vec![Segment::new(Shape::Constant, x, x)])
+    }
+
+    pub fn new(shape: Shape, from: f64, to: f64) -> Curve {
+        Curve(vec![Segment::new(shape, from, to)])
+    }
+
+    /// Value for iteration `q` of `len`, with `from` and `to` multiplied by `scale`
+    pub fn at(&self, q: usize, len: usize, scale: f64) -> f64 {
+        let Some(s) = self
+            .0
+            .iter()
+            .find(|s| s.until.is_none_or(|u| q < u))
+            .or(self.0.last())
+        else {
+            return 0.0;
+        };
+        let from = scale * s.from;
+        let to = scale * s.to.unwrap_or(s.from);
+        let done = if s.at_start { q } else { q + 1 };
+        let t = done as f64 / len as f64;
+        match s.shape {
+            Shape::Constant => from,
+            Shape::Linear => lerp(&from, &to, &t),
+            Shape::Exponential => libm::exp(lerp(&libm::log(from), &libm::log(to), &t)),
+            Shape::Cosine => lerp(
+                &from,
+                &to,
+                &((1.0 - libm::cos(std::f64::consts::PI * t)) / 2.0),
+            ),
+            Shape::SqrtLerp => lerp(&(from * from), &(to * to), &t).sqrt(),
+        }
+    }
+
+    fn check(&self, what: &str, phase: &str) -> anyhow::Result<()> {
+        if self.0.is_empty() {
+            anyhow::bail!("{what} of phase {phase} has no segments");
+        }
+        for s in &self.0 {
+            let values = [s.from, s.to.unwrap_or(s.from)];
+            if values.iter().any(|x| !x.is_finite()) {
+                anyhow::bail!("{what} of phase {phase} should be finite");
+            }
+            if s.shape == Shape::Exponential && values.iter().any(|&x| x <= 0.0) {
+                anyhow::bail!("{what} of phase {phase} should be positive for exponential shape");
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Schedule {
+    /// Phases from `--schedule` file or the usual ones according to flags
+    pub fn new(opts: &Csvdimreduce) -> anyhow::Result<Schedule> {
+        let mut schedule = match opts.schedule {
+            Some(ref path) => Schedule::load(path)?,
+            None => Schedule::from_flags(opts),
+        };
+        let central_force = opts.central_force.unwrap_or(DEFAULT_CENTRAL_FORCE);
+        let squeezed_force = opts.squeeze_final_force.unwrap_or(10.0 * central_force);
+        for (i, p) in schedule.phases.iter_mut().enumerate() {
+            if p.name.is_empty() {
+                p.name = format!("phase{}", i + 1);
+            }
+            let retain = *p.retain_coords.get_or_insert(opts.n_out_coords);
+            if retain > opts.n_out_coords {
+                anyhow::bail!(
+                    "Phase {} retains {retain} coordinates, but there are only {}",
+                    p.name,
+                    opts.n_out_coords
+                );
+            }
+            p.central_force
+                .get_or_insert_with(|| Curve::constant(central_force));
+            let squeezed_force = *p.squeezed_force.get_or_insert(squeezed_force);
+            p.squeeze_force
+                .get_or_insert_with(|| Curve::constant(squeezed_force));
+            p.inertia.get_or_insert(
+                opts.inertia_multiplier
+                    .unwrap_or(DEFAULT_INERTIA_MULTIPLIER),
+            );
+            p.rate.check("Rate", &p.name)?;
+            p.central_force
+                .as_ref()
+                .unwrap()
+                .check("Central force", &p.name)?;
+            p.squeeze_force
+                .as_ref()
+                .unwrap()
+                .check("Squeeze force", &p.name)?;
+        }
+        Ok(schedule)
+    }
+
+    /// TOML file with `[[phase]]` tables, or JSON with `phase` array if the extension is `.json`
+    fn load(path: &Path) -> anyhow::Result<Schedule> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("Failed to read schedule {}: {e}", path.display()))?;
+        let schedule = if path
+            .extension()
+            .is_some_and(|x| x.eq_ignore_ascii_case("json"))
+        {
+            serde_json::from_str(&text)?
+        } else {
+            toml::from_str(&text)?
+        };
+        Ok(schedule)
+    }
+
+    /// Basic iterations with warmup, then ramping up squeeze force of each squeezed coordinate in turn,
+    /// then final squeeze iterations
+    fn from_flags(opts: &Csvdimreduce) -> Schedule {
+        let crate::flags::Iterations {
+            basic,
+            warmup,
+            squeeze_rampup,
+            squeeze_final,
+            coords_to_squeeze,
+        } = opts.iterations();
+        let n = opts.n_out_coords;
+        let rate = opts.rate.unwrap_or(DEFAULT_RATE);
+        let final_rate = opts.final_rate.unwrap_or(0.02 * rate);
+        let central_force = opts.central_force.unwrap_or(DEFAULT_CENTRAL_FORCE);
+        let squeeze_final_force = opts.squeeze_final_force.unwrap_or(10.0 * central_force);
+        let squeeze_rampup_rate = opts.squeeze_rampup_rate.unwrap_or(rate * 0.2);
+        let squeeze_final_initial_rate = opts
+            .squeeze_final_initial_rate
+            .unwrap_or(squeeze_rampup_rate);
+
+        let phase = |name: &str, iterations, rate, retain_coords, squeeze_force| Phase {
+            name: name.to_owned(),
+            iterations,
+            rate,
+            central_force: None,
+            retain_coords: Some(retain_coords),
+            squeeze_force: Some(squeeze_force),
+            squeezed_force: None,
+            inertia: None,
+            reset_inertia: false,
+        };
+        // Rate does not decay before squeezing
+        let basic_rate = if squeeze_final == 0 {
+            Segment::new(Shape::SqrtLerp, rate, final_rate)
+        } else {
+            Segment::new(Shape::Constant, rate, rate)
+        };
+        let warmup_rate = Segment {
+            until: Some(warmup),
+            ..Segment::new(Shape::Linear, rate * 0.1, rate)
+        };
+        let mut phases = vec![phase(
+            "basic",
+            basic,
+            Curve(vec![warmup_rate, basic_rate]),
+            n,
+            Curve::constant(central_force),
+        )];
+        for squeezed in 1..=coords_to_squeeze {
+            phases.push(Phase {
+                reset_inertia: true,
+                ..phase(
+                    "squeeze_rampup",
+                    squeeze_rampup,
+                    Curve::constant(squeeze_rampup_rate),
+                    n - squeezed,
+                    Curve::new(Shape::Exponential, central_force, squeeze_final_force),
+                )
+            });
+        }
+        let final_rate = Segment {
+            at_start: true,
+            ..Segment::new(Shape::SqrtLerp, squeeze_final_initial_rate, final_rate)
+        };
+        phases.push(phase(
+            "squeeze_final",
+            squeeze_final,
+            Curve(vec![final_rate]),
+            n - coords_to_squeeze,
+            Curve::constant(squeeze_final_force),
+        ));
+        Schedule { phases }
+    }
+
+    pub fn total_iterations(&self) -> usize {
+        self.phases.iter().map(|p| p.iterations).sum()
+    }
+}
+
+impl Phase {
+    /// Set parameters that stay the same during the phase
+    pub fn start(&self, params: &mut Params, avgaff: f64) {
+        params.squeeze_from = self.retain_coords.unwrap_or_default();
+        params.squeeze_force2 = avgaff * self.squeezed_force.unwrap_or_default();
+        params.inertia_multiplier = self.inertia.unwrap_or(DEFAULT_INERTIA_MULTIPLIER);
+    }
+
+    /// Set parameters for iteration `q` of the phase
+    pub fn update(&self, params: &mut Params, q: usize, avgaff: f64) {
+        let n = self.iterations;
+        params.rate = self.rate.at(q, n, 1.0);
+        if let Some(ref c) = self.central_force {
+            params.central_force = c.at(q, n, avgaff);
+        }
+        if let Some(ref c) = self.squeeze_force {
+            params.squeeze_force = c.at(q, n, avgaff);
+        }
+    }
+}
+
+/// Number of iterations of the whole schedule, for estimates.
+/// Errors in the schedule file are reported before that.
+pub fn total_iterations(opts: &Csvdimreduce) -> usize {
+    Schedule::new(opts).map_or(0, |s| s.total_iterations())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn opts(args: &[&str]) -> Csvdimreduce {
+        Csvdimreduce::from_vec(args.iter().map(Into::into).collect()).unwrap()
+    }
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() <= 1e-12 * a.abs().max(b.abs()).max(1.0)
+    }
+
+    fn segment(shape: Shape, from: f64, to: f64) -> Curve {
+        Curve::new(shape, from, to)
+    }
+
+    #[test]
+    fn curve_endpoints() {
+        let c = Curve::constant(3.0);
+        assert_eq!((c.at(0, 10, 1.0), c.at(9, 10, 2.0)), (3.0, 6.0));
+
+        let c = segment(Shape::Linear, 1.0, 3.0);
+        assert_eq!((c.at(0, 4, 1.0), c.at(3, 4, 1.0)), (1.5, 3.0));
+
+        let c = segment(Shape::Exponential, 1.0, 100.0);
+        assert!(close(c.at(0, 2, 1.0), 10.0));
+        assert!(close(c.at(1, 2, 1.0), 100.0));
+
+        let c = segment(Shape::Cosine, 0.0, 2.0);
+        assert!(close(c.at(0, 2, 1.0), 1.0));
+        assert!(close(c.at(1, 2, 1.0), 2.0));
+        assert!(c.at(0, 4, 1.0) < 0.25 * 2.0);
+
+        let c = segment(Shape::SqrtLerp, 3.0, 4.0);
+        assert!(close(c.at(0, 2, 1.0), 12.5f64.sqrt()));
+        assert!(close(c.at(1, 2, 1.0), 4.0));
+
+        let c = Curve(vec![Segment {
+            at_start: true,
+            ..Segment::new(Shape::Linear, 1.0, 3.0)
+        }]);
+        assert_eq!((c.at(0, 4, 1.0), c.at(3, 4, 1.0)), (1.0, 2.5));
+
+        assert_eq!(Curve(vec![]).at(0, 1, 1.0), 0.0);
+    }
+
+    #[test]
+    fn until_segments() {
+        let c = Curve(vec![
+            Segment {
+                until: Some(5),
+                ..Segment::new(Shape::Linear, 0.0, 10.0)
+            },
+            Segment {
+                until: Some(8),
+                ..Segment::new(Shape::Constant, 7.0, 7.0)
+            },
+            Segment::new(Shape::Linear, 0.0, 1.0),
+        ]);
+        let values: Vec<f64> = (0..10).map(|q| c.at(q, 10, 1.0)).collect();
+        assert_eq!(values, [1.0, 2.0, 3.0, 4.0, 5.0, 7.0, 7.0, 7.0, 0.9, 1.0]);
+
+        // Past the last `until`, the last segment still applies
+        let c = Curve(vec![Segment {
+            until: Some(2),
+            ..Segment::new(Shape::Linear, 0.0, 4.0)
+        }]);
+        assert_eq!(c.at(3, 4, 1.0), 4.0);
+    }
+
+    #[test]
+    fn parse_toml_and_json() {
+        let toml: Schedule = toml::from_str(
+            r#"
+            [[phase]]
+            name = "spread"
+            iterations = 10
+            rate = 0.01
+
+            [[phase]]
+            iterations = 20
+            rate = { shape = "exponential", from = 0.01, to = 0.001 }
+            central_force = [
+                { shape = "linear", from = 1, to = 2, until = 5 },
+                { shape = "constant", from = 2 },
+            ]
+            retain_coords = 2
+            reset_inertia = true
+            "#,
+        )
+        .unwrap();
+        let json: Schedule = serde_json::from_str(
+            r#"{ "phases": [
+                { "name": "spread", "iterations": 10, "rate": 0.01 },
+                {
+                    "iterations": 20,
+                    "rate": { "shape": "exponential", "from": 0.01, "to": 0.001 },
+                    "central_force": [
+                        { "shape": "linear", "from": 1, "to": 2, "until": 5 },
+                        { "shape": "constant", "from": 2 }
+                    ],
+                    "retain_coords": 2,
+                    "reset_inertia": true
+                }
+            ] }"#,
+        )
+        .unwrap();
+        for s in [toml, json] {
+            assert_eq!(s.total_iterations(), 30);
+            let [first, second] = &s.phases[..] else {
+                panic!("{s:?}")
+            };
+            assert_eq!(first.name, "spread");
+            assert_eq!(first.rate.at(9, 10, 1.0), 0.01);
+            assert_eq!(second.retain_coords, Some(2));
+            assert!(second.reset_inertia);
+            assert!(close(second.rate.at(19, 20, 1.0), 0.001));
+            let central = second.central_force.as_ref().unwrap();
+            assert_eq!(
+                (central.at(4, 20, 1.0), central.at(5, 20, 1.0)),
+                (1.25, 2.0)
+            );
+        }
+
+        let unknown =
+            toml::from_str::<Schedule>("[[phase]]\niterations = 1\nrate = 1\nspeed = 2\n");
+        assert!(unknown.is_err());
+    }
+
+    /// Rate, squeezed coordinate, its force and central force of each iteration,
+    /// computed like before schedules were introduced
+    fn three_phases(opts: &Csvdimreduce, avgaff: f64) -> Vec<(f64, usize, f64, f64)> {
+        let n_iters = opts.n_iters.unwrap_or(100);
+        let warmup_iters = opts.warmup_iterations.unwrap_or(n_iters / 2);
+        let rate = opts.rate.unwrap_or(0.01);
+        let final_rate = opts.final_rate.unwrap_or(0.02 * rate);
+        let central_force = avgaff * opts.central_force.unwrap_or(20.0);
+        let squeeze_rampup_rate = opts.squeeze_rampup_rate.unwrap_or(rate * 0.2);
+        let squeezing = opts.retain_coords_from_squeezing.is_some();
+        let squeeze_rampup_iters =
+            opts.squeeze_rampup_iters
+                .unwrap_or(if squeezing { n_iters } else { 0 });
+        let squeeze_final_iters =
+            opts.squeeze_final_iters
+                .unwrap_or(if squeezing { n_iters } else { 0 });
+        let squeeze_final_force = avgaff
+            * opts
+                .squeeze_final_force
+                .unwrap_or(10.0 * opts.central_force.unwrap_or(20.0));
+        let squeeze_final_initial_rate = opts
+            .squeeze_final_initial_rate
+            .unwrap_or(squeeze_rampup_rate);
+        let n = opts.n_out_coords;
+
+        let mut steps = vec![];
+        for q in 0..n_iters {
+            let t = (q + 1) as f64 / n_iters as f64;
+            let r = if q < warmup_iters {
+                lerp(&(rate * 0.1), &rate, &t)
+            } else if squeeze_final_iters == 0 {
+                lerp(&(rate * rate), &(final_rate * final_rate), &t).sqrt()
+            } else {
+                rate
+            };
+            steps.push((r, n, central_force, central_force));
+        }
+        let coords_to_squeeze = n.saturating_sub(opts.retain_coords_from_squeezing.unwrap_or(n));
+        for squeezed in 1..=coords_to_squeeze {
+            for q in 0..squeeze_rampup_iters {
+                let t = (q + 1) as f64 / squeeze_rampup_iters as f64;
+                let force = lerp(&central_force.ln(), &squeeze_final_force.ln(), &t).exp();
+                steps.push((squeeze_rampup_rate, n - squeezed, force, central_force));
+            }
+        }
+        let mut r = squeeze_final_initial_rate;
+        for q in 0..squeeze_final_iters {
+            steps.push((r, n - coords_to_squeeze, squeeze_final_force, central_force));
+            let t = (q + 1) as f64 / squeeze_final_iters as f64;
+            r = lerp(
+                &(squeeze_final_initial_rate * squeeze_final_initial_rate),
+                &(final_rate * final_rate),
+                &t,
+            )
+            .sqrt();
+        }
+        steps
+    }
+
+    #[test]
+    fn flags_give_three_phases() {
+        let avgaff = 0.7;
+        for args in [
+            &["1:2", "2"][..],
+            &["1:2", "3", "-S", "1", "-n", "30"],
+            &[
+                "1:2", "4", "-S", "2", "-n", "20", "-r", "0.05", "-c", "5", "-C", "80",
+            ],
+            &[
+                "1:2",
+                "2",
+                "-n",
+                "10",
+                "--warmup-iterations",
+                "3",
+                "--squeeze-final-iters",
+                "4",
+            ],
+        ] {
+            let opts = opts(args);
+            let schedule = Schedule::new(&opts).unwrap();
+            let names: Vec<&str> = schedule.phases.iter().map(|p| p.name.as_str()).collect();
+            let squeezed = opts.iterations().coords_to_squeeze;
+            let mut expected = vec!["basic"];
+            expected.extend(std::iter::repeat_n("squeeze_rampup", squeezed));
+            expected.push("squeeze_final");
+            assert_eq!(names, expected, "{args:?}");
+
+            let mut params = Params {
+                rate: 0.0,
+                central_force: 0.0,
+                squeeze_from: 0,
+                squeeze_force: 0.0,
+                squeeze_force2: 0.0,
+                inertia_multiplier: 0.0,
+                force_law: Default::default(),
+                debug: false,
+            };
+            let mut steps = vec![];
+            for phase in &schedule.phases {
+                phase.start(&mut params, avgaff);
+                for q in 0..phase.iterations {
+                    phase.update(&mut params, q, avgaff);
+                    steps.push((
+                        params.rate,
+                        params.squeeze_from,
+                        params.squeeze_force,
+                        params.central_force,
+                    ));
+                }
+            }
+            let old = three_phases(&opts, avgaff);
+            assert_eq!(steps.len(), old.len(), "{args:?}");
+            for (i, (a, b)) in steps.iter().zip(&old).enumerate() {
+                let squeezing = a.1 < opts.n_out_coords;
+                assert!(
+                    close(a.0, b.0)
+                        && a.1 == b.1
+                        && (!squeezing || close(a.2, b.2))
+                        && close(a.3, b.3),
+                    "{args:?} iteration {i}: {a:?} != {b:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn too_many_retained_coords() {
+        let path = std::env::temp_dir().join(format!("csvdimreduce_{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            "[[phase]]\niterations = 1\nrate = 1\nretain_coords = 3\n",
+        )
+        .unwrap();
+        let e =
+            Schedule::new(&opts(&["1:2", "2", "--schedule", path.to_str().unwrap()])).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "Phase phase1 retains 3 coordinates, but there are only 2"
+        );
+    }
+}
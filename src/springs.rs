@@ -0,0 +1,104 @@
+//! Attraction of each particle to its nearest neighbours in input space
+
+use ndarray::{ArrayView1, ArrayView2, ArrayViewMut1};
+
+use crate::affinities::Affinities;
+use crate::algorithm::{Real, MIN_SQNORM};
+use crate::flags::Csvdimreduce;
+
+pub const DEFAULT_SPRING_CONSTANT: f64 = 3000.0;
+pub const DEFAULT_SPRING_LENGTH: f64 = 0.0;
+
+pub struct Springs {
+    /// Number of neighbours of each particle
+    pub k: usize,
+    /// `k` nearest neighbours of each particle, particle after particle
+    pub neighbors: Vec<usize>,
+    /// Total pull of all springs of a particle per unit of their extension, like `central_force`
+    pub constant: f64,
+    pub rest_length: f64,
+}
+
+impl Springs {
+    /// Springs to nearest neighbours by affinity, if `--neighbors` is specified.
+    /// `avgaff` scales the spring constant like the central force.
+    pub fn new(opts: &Csvdimreduce, affinities: &Affinities, avgaff: f64) -> Option<Springs> {
+        let k = opts.neighbors.filter(|&k| k > 0)?;
+        let param = opts.spring_constant.unwrap_or(DEFAULT_SPRING_CONSTANT);
+        let mut springs = Springs {
+            k,
+            neighbors: vec![],
+            constant: avgaff * param,
+            rest_length: opts.spring_length.unwrap_or(DEFAULT_SPRING_LENGTH),
+        };
+        springs.connect(affinities);
+        Some(springs)
+    }
+
+    /// The same springs between other particles, like ones of a `--multilevel` level
+    pub fn reconnect(&self, affinities: &Affinities) -> Springs {
+        let mut springs = Springs {
+            neighbors: vec![],
+            ..*self
+        };
+        springs.connect(affinities);
+        springs
+    }
+
+    /// Find `k` particles with the smallest affinity (which means the most similar rows) to each one
+    fn connect(&mut self, affinities: &Affinities) {
+        let n = affinities.len();
+        let k = self.k.min(n.saturating_sub(1));
+        self.k = k;
+        self.neighbors.clear();
+        self.neighbors.reserve(n * k);
+        let mut candidates = Vec::with_capacity(n);
+        for j in 0..n {
+            candidates.clear();
+            candidates.extend(
+                (0..n)
+                    .filter(|&p| p != j)
+                    .map(|p| (affinities.get(j, p), p)),
+            );
+            if k > 0 {
+                candidates.select_nth_unstable_by(k - 1, |a, b| a.0.total_cmp(&b.0));
+            }
+            self.neighbors.extend(candidates[..k].iter().map(|x| x.1));
+        }
+    }
+
+    /// Add pull of particle `j` towards its neighbours to `forces`.
+    /// Like other forces, it is proportional to the number of particles.
+    pub fn add_attraction<T: Real>(
+        &self,
+        j: usize,
+        coords: ArrayView2<'_, T>,
+        weights: ArrayView1<'_, T>,
+        mut forces: ArrayViewMut1<'_, T>,
+    ) {
+        let n = coords.nrows() as f64;
+        let my_coords = coords.row(j);
+        let my_weight: f64 = weights[j].into();
+        for &p in &self.neighbors[j * self.k..(j + 1) * self.k] {
+            let their_coords = coords.row(p);
+            let sqnorm: f64 = my_coords
+                .iter()
+                .zip(&their_coords)
+                .map(|(&a, &b)| {
+                    let d: f64 = (b - a).into();
+                    d * d
+                })
+                .sum();
+            let norm = sqnorm.max(MIN_SQNORM).sqrt();
+            let their_weight: f64 = weights[p].into();
+            // Hooke's law, also divided by norm to make direction a unit vector.
+            // Springs shorter than the rest length push particles apart.
+            let pull = self.constant * n / self.k as f64 * (norm - self.rest_length) / norm
+                * their_weight
+                / my_weight;
+            for (f, (&a, &b)) in forces.iter_mut().zip(my_coords.iter().zip(&their_coords)) {
+                *f += T::of(pull * (b - a).into());
+            }
+        }
+    }
+}
@@ -0,0 +1,199 @@
+//! `--stability`: how much position and neighbourhood of each row vary between simulations from different seeds
+
+use ndarray::{s, Array1, ArrayView2, Axis};
+
+use crate::flags::Csvdimreduce;
+use crate::Arr2;
+
+/// Sweeps of the Jacobi eigenvalue algorithm, far more than a few coordinates need to converge
+const JACOBI_SWEEPS: usize = 100;
+
+/// Names of columns appended after mean coordinates by [`analyze`]
+pub fn column_names(opts: &Csvdimreduce) -> Vec<String> {
+    let mut names = vec!["position_sd".to_owned()];
+    if opts.stability_neighbors.is_some() {
+        names.push("neighbor_jaccard".to_owned());
+    }
+    names
+}
+
+/// Mean coordinates of each row over all `runs` aligned to the first one, followed by columns of [`column_names`]
+pub fn analyze(opts: &Csvdimreduce, runs: &[Arr2]) -> anyhow::Result<Arr2> {
+    let reference = runs[0].view();
+    let (n, d) = reference.dim();
+    let extra = column_names(opts).len();
+    if n == 0 {
+        // All rows skipped or none in the input, like without `--stability`
+        return Ok(Arr2::zeros((0, d + extra)));
+    }
+    let aligned: Vec<Arr2> = runs.iter().map(|y| align(reference, y.view())).collect();
+
+    let mut mean = Arr2::zeros((n, d));
+    for y in &aligned {
+        mean += y;
+    }
+    mean /= runs.len() as f64;
+    let mut variance = Array1::<f64>::zeros(n);
+    for y in &aligned {
+        let diff = y - &mean;
+        variance += &(&diff * &diff).sum_axis(Axis(1));
+    }
+    let sd = (variance / runs.len() as f64).mapv(f64::sqrt);
+
+    let mut result = Arr2::zeros((n, d + extra));
+    result.slice_mut(s![.., ..d]).assign(&mean);
+    result.column_mut(d).assign(&sd);
+    if let Some(k) = opts.stability_neighbors {
+        if k == 0 || k >= n {
+            anyhow::bail!("--stability-neighbors should be from 1 to the number of rows minus one");
+        }
+        let neighbors: Vec<Vec<usize>> = runs.iter().map(|y| nearest(y.view(), k)).collect();
+        result.column_mut(d + 1).assign(&jaccard(&neighbors, n, k));
+    }
+    Ok(result)
+}
+
+/// `y` rotated, reflected and translated to be as close to `reference` as possible (orthogonal Procrustes)
+fn align(reference: ArrayView2<'_, f64>, y: ArrayView2<'_, f64>) -> Arr2 {
+    let reference_center = reference.mean_axis(Axis(0)).unwrap();
+    let y_center = y.mean_axis(Axis(0)).unwrap();
+    let x = &reference - &reference_center;
+    let y = &y - &y_center;
+    let rotation = orthogonal_factor(y.t().dot(&x));
+    y.dot(&rotation) + &reference_center
+}
+
+/// Orthogonal matrix `U Vᵀ` from singular value decomposition `m = U Σ Vᵀ`, which maps `y` onto `x` for `m = yᵀ x`.
+/// Directions with zero singular values, like squeezed coordinates, get arbitrary orthonormal completion.
+fn orthogonal_factor(m: Arr2) -> Arr2 {
+    let d = m.nrows();
+    let (eigenvalues, v) = symmetric_eigen(m.t().dot(&m));
+    let largest = eigenvalues.iter().copied().fold(0.0, f64::max).sqrt();
+    let mut u = Arr2::zeros((d, d));
+    let mut found = vec![false; d];
+    for i in 0..d {
+        let sigma = eigenvalues[i].max(0.0).sqrt();
+        if sigma > 1e-12 * largest {
+            u.column_mut(i).assign(&(m.dot(&v.column(i)) / sigma));
+            found[i] = true;
+        }
+    }
+    // Gram-Schmidt on standard basis vectors for the rest
+    let mut basis = 0;
+    for i in 0..d {
+        while !found[i] && basis < d {
+            let mut c = Array1::<f64>::zeros(d);
+            c[basis] = 1.0;
+            basis += 1;
+            for j in (0..d).filter(|&j| found[j]) {
+                let projection = u.column(j).dot(&c);
+                c.scaled_add(-projection, &u.column(j));
+            }
+            let norm = c.dot(&c).sqrt();
+            if norm > 0.5 {
+                u.column_mut(i).assign(&(c / norm));
+                found[i] = true;
+            }
+        }
+    }
+    u.dot(&v.t())
+}
+
+/// Eigenvalues and eigenvectors (columns) of a symmetric matrix by the cyclic Jacobi method
+fn symmetric_eigen(mut a: Arr2) -> (Vec<f64>, Arr2) {
+    let d = a.nrows();
+    let mut v = Arr2::eye(d);
+    let total: f64 = a.iter().map(|x| x * x).sum();
+    for _ in 0..JACOBI_SWEEPS {
+        let off: f64 = (0..d)
+            .flat_map(|p| (p + 1..d).map(move |q| (p, q)))
+            .map(|(p, q)| a[(p, q)] * a[(p, q)])
+            .sum();
+        if off <= 1e-30 * total {
+            break;
+        }
+        for p in 0..d {
+            for q in p + 1..d {
+                if a[(p, q)] == 0.0 {
+                    continue;
+                }
+                let theta = (a[(q, q)] - a[(p, p)]) / (2.0 * a[(p, q)]);
+                let t = theta.signum() / (theta.abs() + (theta * theta + 1.0).sqrt());
+                let c = 1.0 / (t * t + 1.0).sqrt();
+                let s = t * c;
+                rotate_columns(&mut a, p, q, c, s);
+                rotate_columns(&mut v, p, q, c, s);
+                for k in 0..d {
+                    let (apk, aqk) = (a[(p, k)], a[(q, k)]);
+                    a[(p, k)] = c * apk - s * aqk;
+                    a[(q, k)] = s * apk + c * aqk;
+                }
+            }
+        }
+    }
+    ((0..d).map(|i| a[(i, i)]).collect(), v)
+}
+
+fn rotate_columns(a: &mut Arr2, p: usize, q: usize, c: f64, s: f64) {
+    for k in 0..a.nrows() {
+        let (akp, akq) = (a[(k, p)], a[(k, q)]);
+        a[(k, p)] = c * akp - s * akq;
+        a[(k, q)] = s * akp + c * akq;
+    }
+}
+
+/// Sorted indices of `k` nearest other rows of each row, ties broken by index
+fn nearest(coords: ArrayView2<'_, f64>, k: usize) -> Vec<usize> {
+    let n = coords.nrows();
+    let mut result = Vec::with_capacity(n * k);
+    let mut distances = vec![0.0; n];
+    let mut order: Vec<usize> = Vec::with_capacity(n);
+    for j in 0..n {
+        for (v, x) in distances.iter_mut().enumerate() {
+            let diff = &coords.row(v) - &coords.row(j);
+            *x = diff.dot(&diff);
+        }
+        order.clear();
+        order.extend((0..n).filter(|&v| v != j));
+        order.select_nth_unstable_by(k - 1, |&a, &b| {
+            distances[a].total_cmp(&distances[b]).then(a.cmp(&b))
+        });
+        order.truncate(k);
+        order.sort_unstable();
+        result.extend_from_slice(&order);
+    }
+    result
+}
+
+/// Jaccard similarity of neighbour sets of each row, averaged over all pairs of runs
+fn jaccard(neighbors: &[Vec<usize>], n: usize, k: usize) -> Array1<f64> {
+    let mut sum = Array1::<f64>::zeros(n);
+    let mut pairs = 0;
+    for (i, a) in neighbors.iter().enumerate() {
+        for b in &neighbors[i + 1..] {
+            for (j, x) in sum.iter_mut().enumerate() {
+                let common = intersection(&a[j * k..(j + 1) * k], &b[j * k..(j + 1) * k]);
+                *x += common as f64 / (2 * k - common) as f64;
+            }
+            pairs += 1;
+        }
+    }
+    sum / pairs as f64
+}
+
+/// Number of common elements of two sorted lists
+fn intersection(a: &[usize], b: &[usize]) -> usize {
+    let (mut i, mut j, mut common) = (0, 0, 0);
+    while i < a.len() && j < b.len() {
+        match a[i].cmp(&b[j]) {
+            std::cmp::Ordering::Less => i += 1,
+            std::cmp::Ordering::Greater => j += 1,
+            std::cmp::Ordering::Equal => {
+                common += 1;
+                i += 1;
+                j += 1;
+            }
+        }
+    }
+    common
+}
@@ -0,0 +1,224 @@
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Copy)]
+pub enum TextMetric {
+    /// Jaccard distance between sets of character n-grams of this size
+    NGrams(usize),
+    /// Cosine distance between TF-IDF vectors of words
+    TfIdf,
+}
+
+/// Prepared values of one text column, one entry per particle
+pub enum TextColumn {
+    /// Sorted, deduplicated hashes of n-grams
+    NGrams(Vec<Vec<u64>>),
+    /// Sorted by term, L2-normalized TF-IDF vectors
+    TfIdf(Vec<Vec<(u32, f64)>>),
+}
+
+impl TextColumn {
+    pub fn new(values: &[String], metric: TextMetric) -> TextColumn {
+        match metric {
+            TextMetric::NGrams(n) => {
+                TextColumn::NGrams(values.iter().map(|x| ngrams(x, n)).collect())
+            }
+            TextMetric::TfIdf => TextColumn::TfIdf(tfidf(values)),
+        }
+    }
+
+    /// Values of the specified particles only
+    pub fn select(&self, rows: &[usize]) -> TextColumn {
+        match self {
+            TextColumn::NGrams(sets) => {
+                TextColumn::NGrams(rows.iter().map(|&j| sets[j].clone()).collect())
+            }
+            TextColumn::TfIdf(vectors) => {
+                TextColumn::TfIdf(rows.iter().map(|&j| vectors[j].clone()).collect())
+            }
+        }
+    }
+
+    /// Distance between two particles, from 0 to 1
+    pub fn distance(&self, j: usize, k: usize) -> f64 {
+        match self {
+            TextColumn::NGrams(sets) => {
+                let (a, b) = (&sets[j], &sets[k]);
+                if a.is_empty() && b.is_empty() {
+                    return 0.0;
+                }
+                let common = count_common(a, b);
+                1.0 - common as f64 / (a.len() + b.len() - common) as f64
+            }
+            TextColumn::TfIdf(vectors) => {
+                let (a, b) = (&vectors[j], &vectors[k]);
+                if a.is_empty() && b.is_empty() {
+                    return 0.0;
+                }
+                (1.0 - sparse_dot(a, b)).max(0.0)
+            }
+        }
+    }
+}
+
+fn ngrams(s: &str, n: usize) -> Vec<u64> {
+    // Pad with spaces, so that word boundaries count
+    let chars: Vec<char> = std::iter::once(' ')
+        .chain(s.trim().chars().flat_map(char::to_lowercase))
+        .chain(std::iter::once(' '))
+        .collect();
+    let mut hashes: Vec<u64> = chars.windows(n.min(chars.len())).map(fnv1a).collect();
+    if s.trim().is_empty() {
+        hashes.clear();
+    }
+    hashes.sort_unstable();
+    hashes.dedup();
+    hashes
+}
+
+/// 64-bit FNV-1a of UTF-8 bytes, which unlike `DefaultHasher` is the same for every Rust release
+fn fnv1a(chars: &[char]) -> u64 {
+    let mut h: u64 = 0xcbf2_9ce4_8422_2325;
+    let mut buf = [0; 4];
+    for c in chars {
+        for &b in c.encode_utf8(&mut buf).as_bytes() {
+            h ^= b as u64;
+            h = h.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+    h
+}
+
+fn count_common(a: &[u64], b: &[u64]) -> usize {
+    let (mut i, mut j, mut c) = (0, 0, 0);
+    while i < a.len() && j < b.len() {
+        match a[i].cmp(&b[j]) {
+            std::cmp::Ordering::Less => i += 1,
+            std::cmp::Ordering::Greater => j += 1,
+            std::cmp::Ordering::Equal => {
+                c += 1;
+                i += 1;
+                j += 1;
+            }
+        }
+    }
+    c
+}
+
+fn sparse_dot(a: &[(u32, f64)], b: &[(u32, f64)]) -> f64 {
+    let (mut i, mut j, mut s) = (0, 0, 0.0);
+    while i < a.len() && j < b.len() {
+        match a[i].0.cmp(&b[j].0) {
+            std::cmp::Ordering::Less => i += 1,
+            std::cmp::Ordering::Greater => j += 1,
+            std::cmp::Ordering::Equal => {
+                s += a[i].1 * b[j].1;
+                i += 1;
+                j += 1;
+            }
+        }
+    }
+    s
+}
+
+fn tfidf(values: &[String]) -> Vec<Vec<(u32, f64)>> {
+    let mut vocabulary = HashMap::<String, u32>::new();
+    let counts: Vec<Vec<(u32, f64)>> = values
+        .iter()
+        .map(|s| {
+            let mut tf = HashMap::<u32, f64>::new();
+            for token in s
+                .split(|c: char| !c.is_alphanumeric())
+                .filter(|x| !x.is_empty())
+            {
+                let n = vocabulary.len() as u32;
+                let term = *vocabulary.entry(token.to_lowercase()).or_insert(n);
+                *tf.entry(term).or_default() += 1.0;
+            }
+            let mut tf: Vec<(u32, f64)> = tf.into_iter().collect();
+            tf.sort_unstable_by_key(|x| x.0);
+            tf
+        })
+        .collect();
+
+    let mut document_frequency = vec![0usize; vocabulary.len()];
+    for doc in &counts {
+        for &(term, _) in doc {
+            document_frequency[term as usize] += 1;
+        }
+    }
+    let n = values.len() as f64;
+    // Smoothed IDF, like in scikit-learn
+    let idf: Vec<f64> = document_frequency
+        .iter()
+        .map(|&df| libm::log((1.0 + n) / (1.0 + df as f64)) + 1.0)
+        .collect();
+
+    counts
+        .into_iter()
+        .map(|mut doc| {
+            for (term, x) in doc.iter_mut() {
+                *x *= idf[*term as usize];
+            }
+            let norm = doc.iter().map(|x| x.1 * x.1).sum::<f64>().sqrt();
+            if norm > 0.0 {
+                for x in doc.iter_mut() {
+                    x.1 /= norm;
+                }
+            }
+            doc
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn column(values: &[&str], metric: TextMetric) -> TextColumn {
+        let values: Vec<String> = values.iter().map(|x| x.to_string()).collect();
+        TextColumn::new(&values, metric)
+    }
+
+    #[test]
+    fn fixed_hash() {
+        // Reference values of FNV-1a
+        assert_eq!(fnv1a(&[]), 0xcbf29ce484222325);
+        assert_eq!(fnv1a(&['a']), 0xaf63dc4c8601ec8c);
+        assert_eq!(fnv1a(&['f', 'o', 'o', 'b', 'a', 'r']), 0x85944171f73967e8);
+    }
+
+    #[test]
+    fn ngram_jaccard() {
+        assert_eq!(ngrams("abc", 3).len(), 3);
+        assert_eq!(ngrams(" aaaa ", 2).len(), 3);
+        let c = column(
+            &["abcd", "abce", "ABCD", "xyz", "", " "],
+            TextMetric::NGrams(3),
+        );
+        // ` ab`, `abc` are common, `bcd`, `cd `, `bce`, `ce ` are not
+        assert_eq!(c.distance(0, 1), 1.0 - 2.0 / 6.0);
+        assert_eq!(c.distance(0, 2), 0.0);
+        assert_eq!(c.distance(0, 3), 1.0);
+        assert_eq!(c.distance(4, 5), 0.0);
+        assert_eq!(c.distance(0, 4), 1.0);
+        // Shorter than n-grams, the whole padded string is one
+        let c = column(&["a", "a", "b"], TextMetric::NGrams(5));
+        assert_eq!((c.distance(0, 1), c.distance(0, 2)), (0.0, 1.0));
+    }
+
+    #[test]
+    fn tfidf_cosine() {
+        let c = column(&["a b", "a c", "d", "B, a!", ""], TextMetric::TfIdf);
+        let n = 5.0;
+        let idf = |df: f64| ((1.0 + n) / (1.0 + df)).ln() + 1.0;
+        let (idf_a, idf_b, idf_c) = (idf(3.0), idf(2.0), idf(1.0));
+        let expected = 1.0
+            - idf_a * idf_a
+                / ((idf_a * idf_a + idf_b * idf_b) * (idf_a * idf_a + idf_c * idf_c)).sqrt();
+        assert!((c.distance(0, 1) - expected).abs() < 1e-12);
+        assert_eq!(c.distance(0, 2), 1.0);
+        assert!(c.distance(0, 3).abs() < 1e-12);
+        assert_eq!(c.distance(2, 4), 1.0);
+        assert_eq!(c.distance(4, 4), 0.0);
+    }
+}
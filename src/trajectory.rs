@@ -0,0 +1,252 @@
+use std::io::Write;
+
+use anyhow::Context;
+use ndarray::{ArrayView2, Axis};
+
+use crate::algorithm::Real;
+use crate::compression::{Compression, OutputStream};
+use crate::flags::Csvdimreduce;
+
+pub type CoordsRef<'a> = ndarray::ArrayView2<'a, f64>;
+
+/// Long-format dump of particle positions over the course of simulation.
+pub enum Trajectory {
+    /// `iteration,phase,row_index,coord1..N` rows
+    Csv(Box<csv::Writer<OutputStream>>),
+    /// Single `(frames, rows, coords)` array of little-endian `f64`s
+    Npy {
+        w: OutputStream,
+        declared_frames: usize,
+        written_frames: usize,
+    },
+}
+
+impl Trajectory {
+    pub fn create(
+        opts: &Csvdimreduce,
+        path: &std::path::Path,
+        n_frames: usize,
+        n_rows: usize,
+        n_out_coords: usize,
+    ) -> anyhow::Result<Trajectory> {
+        let f = crate::flags::create_output_file(path)
+            .with_context(|| format!("Failed to create trajectory file {}", path.display()))?;
+        let is_npy = crate::compression::strip_extension(path)
+            .extension()
+            .is_some_and(|x| x.eq_ignore_ascii_case("npy"));
+        if is_npy {
+            let mut w = f;
+            write_npy_header(&mut w, &[n_frames, n_rows, n_out_coords])?;
+            Ok(Trajectory::Npy {
+                w,
+                declared_frames: n_frames,
+                written_frames: 0,
+            })
+        } else {
+            let mut w = opts.get_csv_writer().from_writer(f);
+            w.write_field("iteration")?;
+            w.write_field("phase")?;
+            w.write_field("row_index")?;
+            for i in 1..=n_out_coords {
+                w.write_field(format!("coord{}", i))?;
+            }
+            w.write_record(None::<&[u8]>)?;
+            Ok(Trajectory::Csv(Box::new(w)))
+        }
+    }
+
+    pub fn write_frame(
+        &mut self,
+        iteration: usize,
+        phase: &str,
+        coords: CoordsRef<'_>,
+    ) -> anyhow::Result<()> {
+        match self {
+            Trajectory::Csv(w) => {
+                for (j, row) in coords.rows().into_iter().enumerate() {
+                    w.write_field(iteration.to_string())?;
+                    w.write_field(phase)?;
+                    w.write_field(j.to_string())?;
+                    for x in row {
+                        w.write_field(format!("{:.4}", x))?;
+                    }
+                    w.write_record(None::<&[u8]>)?;
+                }
+            }
+            Trajectory::Npy {
+                w,
+                declared_frames,
+                written_frames,
+            } => {
+                if *written_frames >= *declared_frames {
+                    anyhow::bail!("Too many frames for the NPY trajectory");
+                }
+                for x in coords.iter() {
+                    w.write_all(&x.to_le_bytes())?;
+                }
+                *written_frames += 1;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn finish(self) -> anyhow::Result<()> {
+        match self {
+            Trajectory::Csv(w) => crate::compression::finish_csv(*w)?,
+            Trajectory::Npy {
+                w,
+                declared_frames,
+                written_frames,
+            } => {
+                if written_frames != declared_frames {
+                    anyhow::bail!(
+                        "NPY trajectory declared {declared_frames} frames, but {written_frames} were written"
+                    );
+                }
+                w.finish()?
+            }
+        }
+        Ok(())
+    }
+}
+
+fn write_npy_header(w: &mut impl Write, shape: &[usize]) -> anyhow::Result<()> {
+    let shape = shape
+        .iter()
+        .map(|x| x.to_string())
+        .collect::<Vec<_>>()
+        .join(", ");
+    let mut header = format!("{{'descr': '<f8', 'fortran_order': False, 'shape': ({shape}), }}");
+    // magic (6) + version (2) + header length (2) + header, padded to 64 bytes, ending with newline
+    let unpadded = 10 + header.len() + 1;
+    header.extend(std::iter::repeat_n(' ', (64 - unpadded % 64) % 64));
+    header.push('\n');
+    w.write_all(b"\x93NUMPY\x01\x00")?;
+    w.write_all(&(header.len() as u16).to_le_bytes())?;
+    w.write_all(header.as_bytes())?;
+    Ok(())
+}
+
+/// Tracks the global iteration counter and saves intermediate positions,
+/// either as a trajectory file or as a series of `debugNNNNN.csv` snapshots.
+pub struct Recorder<'a> {
+    opts: &'a Csvdimreduce,
+    header: &'a Option<csv::ByteRecord>,
+    records: &'a [csv::ByteRecord],
+    skipped: &'a [(usize, csv::ByteRecord)],
+    each: Option<usize>,
+    trajectory: Option<Trajectory>,
+    pub total_iter_count: usize,
+    /// Particle containing each row, when particles are merged by `--multilevel`
+    pub particle_of_row: Option<Vec<usize>>,
+    /// Number of iterations of each run of consecutive iterations with the same phase name
+    phases: Vec<(String, usize)>,
+}
+
+impl<'a> Recorder<'a> {
+    pub fn new(
+        opts: &'a Csvdimreduce,
+        header: &'a Option<csv::ByteRecord>,
+        records: &'a [csv::ByteRecord],
+        skipped: &'a [(usize, csv::ByteRecord)],
+        n_out_coords: usize,
+        total_iters: usize,
+    ) -> anyhow::Result<Recorder<'a>> {
+        let mut each = opts.save_each_n_iters;
+        if each == Some(0) {
+            anyhow::bail!("--save-each-n-iters must be positive");
+        }
+        let trajectory = if let Some(ref path) = opts.trajectory {
+            let each = *each.get_or_insert(1);
+            // One frame before each selected iteration, plus the final one
+            let n_frames = total_iters.div_ceil(each) + 1;
+            Some(Trajectory::create(
+                opts,
+                path,
+                n_frames,
+                records.len(),
+                n_out_coords,
+            )?)
+        } else {
+            None
+        };
+        Ok(Recorder {
+            opts,
+            header,
+            records,
+            skipped,
+            each,
+            trajectory,
+            total_iter_count: 0,
+            particle_of_row: None,
+            phases: vec![],
+        })
+    }
+
+    /// Call before each iteration of the simulation
+    pub fn record<T: Real>(
+        &mut self,
+        phase: &str,
+        coords: ArrayView2<'_, T>,
+    ) -> anyhow::Result<()> {
+        if let Some(se) = self.each {
+            if self.total_iter_count.is_multiple_of(se) {
+                let coords = match self.particle_of_row {
+                    Some(ref p) => coords.select(Axis(0), p).mapv(Into::into),
+                    None => coords.mapv(Into::into),
+                };
+                let coords = coords.view();
+                if let Some(ref mut t) = self.trajectory {
+                    t.write_frame(self.total_iter_count, phase, coords)?;
+                } else {
+                    let compression = self
+                        .opts
+                        .output
+                        .as_deref()
+                        .map_or(Compression::None, Compression::from_path);
+                    let path = format!(
+                        "debug{:05}.csv{}",
+                        self.total_iter_count,
+                        compression.extension()
+                    );
+                    let f = crate::flags::create_output_file(path.as_ref())
+                        .with_context(|| format!("Failed to create snapshot file {path}"))?;
+                    let f = self.opts.get_csv_writer().from_writer(f);
+                    crate::save_csv(
+                        self.header,
+                        &crate::coord_names(coords.ncols()),
+                        f,
+                        self.records,
+                        self.skipped,
+                        &[coords],
+                        false,
+                    )
+                    .with_context(|| format!("Failed to write snapshot file {path}"))?;
+                }
+            }
+        }
+        match self.phases.last_mut() {
+            Some((p, n)) if p == phase => *n += 1,
+            _ => self.phases.push((phase.to_owned(), 1)),
+        }
+        self.total_iter_count += 1;
+        Ok(())
+    }
+
+    /// Write the final positions to the trajectory and flush it.
+    /// Returns names of phases and numbers of iterations done in them.
+    pub fn finish<T: Real>(
+        self,
+        coords: ArrayView2<'_, T>,
+    ) -> anyhow::Result<Vec<(String, usize)>> {
+        if let Some(mut t) = self.trajectory {
+            t.write_frame(
+                self.total_iter_count,
+                "final",
+                coords.mapv(Into::into).view(),
+            )?;
+            t.finish()?;
+        }
+        Ok(self.phases)
+    }
+}
@@ -0,0 +1,774 @@
+//! Behaviour of the command line on inputs other than `sample.csv`
+
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+/// Write `files` (name and content) to a fresh directory and run csvdimreduce there
+fn run(test: &str, files: &[(&str, &str)], args: &[&str]) -> (PathBuf, Output) {
+    let dir: PathBuf = [env!("CARGO_TARGET_TMPDIR"), test].iter().collect();
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    for (name, content) in files {
+        std::fs::write(dir.join(name), content).unwrap();
+    }
+    let output = run_in(&dir, args);
+    (dir, output)
+}
+
+fn run_in(dir: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_csvdimreduce"))
+        .args(args)
+        .current_dir(dir)
+        .output()
+        .unwrap()
+}
+
+fn stdout(output: &Output) -> &str {
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    std::str::from_utf8(&output.stdout).unwrap()
+}
+
+#[test]
+fn stability_without_rows() {
+    let (_, output) = run(
+        "stability_without_rows",
+        &[("empty.csv", "a,b\n")],
+        &["1:2", "2", "--stability", "2", "empty.csv"],
+    );
+    assert_eq!(stdout(&output), "coord1,coord2,position_sd,a,b\n");
+
+    let (_, output) = run(
+        "stability_all_skipped",
+        &[("bad.csv", "a,b\nx,y\n")],
+        &[
+            "1:2",
+            "2",
+            "--stability",
+            "2",
+            "--on-error",
+            "skip",
+            "bad.csv",
+        ],
+    );
+    assert_eq!(stdout(&output), "coord1,coord2,position_sd,a,b\n");
+}
+
+#[test]
+fn command_line_column_type_overrides_config() {
+    let files = [
+        (
+            "dates.csv",
+            "a,b\n1,2023-01-01\n2,2023-01-02\n3,2023-01-05\n",
+        ),
+        ("date.toml", "column-type = [\"2=date\"]\n"),
+        ("number.toml", "column-type = [\"2=number\"]\n"),
+    ];
+    let args = |column_type, config| {
+        [
+            "1:2",
+            "2",
+            "-n",
+            "5",
+            "--column-type",
+            column_type,
+            "--config",
+            config,
+            "dates.csv",
+        ]
+    };
+    let (_, output) = run(
+        "column_type_cli_date",
+        &files,
+        &args("2=date", "number.toml"),
+    );
+    stdout(&output);
+    let (_, output) = run(
+        "column_type_cli_number",
+        &files,
+        &args("2=number", "date.toml"),
+    );
+    assert!(!output.status.success(), "dates parsed as numbers");
+}
+
+#[test]
+fn dumped_config_of_matrix_input() {
+    let args = [
+        "2:5",
+        "2",
+        "-n",
+        "20",
+        "--input-mode",
+        "matrix",
+        "square.csv",
+    ];
+    let (dir, output) = run(
+        "dump_config_matrix",
+        &[("square.csv", SQUARE)],
+        &[&args[..], &["--dump-config"]].concat(),
+    );
+    std::fs::write(dir.join("dumped.toml"), stdout(&output)).unwrap();
+    let expected = stdout(&run_in(&dir, &args)).to_owned();
+    let output = run_in(&dir, &[&args[..], &["--config", "dumped.toml"]].concat());
+    assert_eq!(stdout(&output), expected);
+}
+
+#[test]
+fn replay_from_another_directory() {
+    let sample =
+        std::fs::read_to_string(concat!(env!("CARGO_MANIFEST_DIR"), "/sample.csv")).unwrap();
+    let (dir, output) = run(
+        "replay_recorded",
+        &[("sample.csv", &sample)],
+        &[
+            "2:3",
+            "2",
+            "-n",
+            "20",
+            "-o",
+            "out.csv",
+            "--metadata",
+            "sample.csv",
+        ],
+    );
+    stdout(&output);
+    let recorded = std::fs::read_to_string(dir.join("out.csv")).unwrap();
+    let metadata = std::fs::read_to_string(dir.join("out.csv.json")).unwrap();
+
+    let (_, output) = run(
+        "replay_elsewhere",
+        &[],
+        &["--replay", dir.join("out.csv.json").to_str().unwrap()],
+    );
+    assert_eq!(stdout(&output), recorded);
+    assert_eq!(
+        std::fs::read_to_string(dir.join("out.csv")).unwrap(),
+        recorded
+    );
+    assert_eq!(
+        std::fs::read_to_string(dir.join("out.csv.json")).unwrap(),
+        metadata
+    );
+}
+
+/// Run `args` with `--metadata` in `test` directory, then `--replay` it from another one.
+/// Returns the directory of the recorded run and output of the replay.
+fn record_and_replay(test: &str, files: &[(&str, &str)], args: &[&str]) -> (PathBuf, Output) {
+    let args: Vec<&str> = args
+        .iter()
+        .copied()
+        .chain(["-o", "out.csv", "--metadata"])
+        .collect();
+    let (dir, output) = run(test, files, &args);
+    stdout(&output);
+    let metadata = dir.join("out.csv.json");
+    let (_, output) = run(
+        &format!("{test}_replayed"),
+        &[],
+        &["--replay", metadata.to_str().unwrap()],
+    );
+    (dir, output)
+}
+
+#[test]
+fn replay_matrix_input() {
+    let (dir, output) = record_and_replay(
+        "replay_matrix",
+        &[("square.csv", SQUARE)],
+        &[
+            "2:5",
+            "2",
+            "-n",
+            "20",
+            "--input-mode",
+            "matrix",
+            "square.csv",
+        ],
+    );
+    let recorded = std::fs::read_to_string(dir.join("out.csv")).unwrap();
+    assert_eq!(stdout(&output), recorded);
+}
+
+#[test]
+fn replay_schedule_and_labels() {
+    let indexed = SQUARE_TRIPLES
+        .replace('a', "0")
+        .replace('b', "1")
+        .replace('c', "2")
+        .replace('d', "3");
+    let schedule = "[[phase]]\niterations = 10\nrate = 0.01\n";
+    let (dir, output) = record_and_replay(
+        "replay_schedule",
+        &[
+            ("square.csv", &indexed),
+            ("corners.csv", "corner\na\nb\nc\nd\n"),
+            ("s.toml", schedule),
+        ],
+        &[
+            "1:3",
+            "2",
+            "--input-mode",
+            "triples",
+            "--labels",
+            "corners.csv",
+            "--schedule",
+            "s.toml",
+            "square.csv",
+        ],
+    );
+    let recorded = std::fs::read_to_string(dir.join("out.csv")).unwrap();
+    assert_eq!(stdout(&output), recorded);
+    assert!(output.stderr.is_empty());
+
+    std::fs::write(dir.join("s.toml"), schedule.replace("10", "11")).unwrap();
+    let output = run_in(&dir, &["--replay", "out.csv.json", "-o", "replayed.csv"]);
+    stdout(&output);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("s.toml differs from the schedule file"),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn replay_does_not_quarantine() {
+    let (dir, output) = record_and_replay(
+        "replay_quarantine",
+        &[("bad.csv", "a,b\n1,2\nx,y\n3,5\n4,4\n")],
+        &[
+            "1:2",
+            "2",
+            "-n",
+            "20",
+            "--on-error",
+            "quarantine=rejected.csv",
+            "bad.csv",
+        ],
+    );
+    let recorded = std::fs::read_to_string(dir.join("out.csv")).unwrap();
+    assert_eq!(stdout(&output), recorded);
+    assert!(std::fs::exists(dir.join("rejected.csv")).unwrap());
+    assert!(!std::fs::exists(
+        dir.with_file_name("replay_quarantine_replayed")
+            .join("rejected.csv")
+    )
+    .unwrap());
+}
+
+#[test]
+fn invalid_disconnected_distance() {
+    for d in ["-1", "inf", "NaN"] {
+        let (_, output) = run(
+            "invalid_disconnected_distance",
+            &[("edges.csv", "a,b\nx,y\nz,w\n")],
+            &[
+                "1:2",
+                "2",
+                "--input-mode",
+                "edges",
+                "--disconnected-distance",
+                d,
+                "edges.csv",
+            ],
+        );
+        assert!(!output.status.success(), "{d} accepted");
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.contains("--disconnected-distance"), "{stderr}");
+    }
+}
+
+/// Corners of a unit square as a distance matrix with a leading name column
+const SQUARE: &str = "name,a,b,c,d\n\
+    a,0,1,1.4,1\n\
+    b,1,0,1,1.4\n\
+    c,1.4,1,0,1\n\
+    d,1,1.4,1,0\n";
+
+/// The same distances as `SQUARE`, each pair listed once
+const SQUARE_TRIPLES: &str = "from,to,distance\n\
+    a,b,1\n\
+    a,c,1.4\n\
+    a,d,1\n\
+    b,c,1\n\
+    b,d,1.4\n\
+    c,d,1\n";
+
+/// Fields of each output line starting from the 0-based column `from`
+fn columns(text: &str, from: usize) -> Vec<String> {
+    text.lines()
+        .map(|line| line.split(',').skip(from).collect::<Vec<_>>().join(","))
+        .collect()
+}
+
+#[test]
+fn dense_matrix() {
+    let files = [("square.csv", SQUARE)];
+    let (_, output) = run(
+        "dense_named",
+        &files,
+        &["2:5", "2", "--input-mode", "matrix", "square.csv"],
+    );
+    let named = stdout(&output).to_owned();
+    assert_eq!(columns(&named, 2), ["name", "a", "b", "c", "d"]);
+
+    // Without the name column, labels are taken from the header
+    let unnamed: String = SQUARE
+        .lines()
+        .map(|line| line.split_once(',').unwrap().1.to_owned() + "\n")
+        .collect();
+    let (_, output) = run(
+        "dense_header_labels",
+        &[("square.csv", &unnamed)],
+        &["1:4", "2", "--input-mode", "matrix", "square.csv"],
+    );
+    assert_eq!(stdout(&output).replace("label", "name"), named);
+
+    let (_, output) = run(
+        "dense_negative",
+        &[("square.csv", &unnamed.replace("1.4,1,0,1", "1.4,1,0,-1"))],
+        &["1:4", "2", "--input-mode", "matrix", "square.csv"],
+    );
+    assert!(!output.status.success(), "negative distance accepted");
+}
+
+#[test]
+fn triples() {
+    let (_, output) = run(
+        "dense_for_triples",
+        &[("square.csv", SQUARE)],
+        &["2:5", "2", "--input-mode", "matrix", "square.csv"],
+    );
+    let dense = stdout(&output).replace("name", "label");
+    let (_, output) = run(
+        "triples",
+        &[("square.csv", SQUARE_TRIPLES)],
+        &["1:3", "2", "--input-mode", "triples", "square.csv"],
+    );
+    assert_eq!(stdout(&output), dense);
+
+    let (_, output) = run(
+        "triples_two_columns",
+        &[("square.csv", SQUARE_TRIPLES)],
+        &["1:2", "2", "--input-mode", "triples", "square.csv"],
+    );
+    assert!(
+        !output.status.success(),
+        "triples without distances accepted"
+    );
+}
+
+#[test]
+fn triples_with_labels() {
+    let indexed = SQUARE_TRIPLES
+        .replace('a', "0")
+        .replace('b', "1")
+        .replace('c', "2")
+        .replace('d', "3");
+    let (_, output) = run(
+        "triples_indexed",
+        &[("square.csv", &indexed)],
+        &["1:3", "2", "--input-mode", "triples", "square.csv"],
+    );
+    let by_index = stdout(&output).to_owned();
+    assert_eq!(columns(&by_index, 2), ["label", "0", "1", "2", "3"]);
+
+    let files = [
+        ("square.csv", indexed.as_str()),
+        (
+            "corners.csv",
+            "corner,side\na,left\nb,left\nc,right\nd,right\n",
+        ),
+    ];
+    let (_, output) = run(
+        "triples_labels",
+        &files,
+        &[
+            "1:3",
+            "2",
+            "--input-mode",
+            "triples",
+            "--labels",
+            "corners.csv",
+            "square.csv",
+        ],
+    );
+    let labelled = stdout(&output);
+    assert_eq!(
+        columns(labelled, 2),
+        ["corner,side", "a,left", "b,left", "c,right", "d,right"]
+    );
+    for (a, b) in by_index.lines().zip(labelled.lines()).skip(1) {
+        assert_eq!(
+            a.split(',').take(2).collect::<Vec<_>>(),
+            b.split(',').take(2).collect::<Vec<_>>()
+        );
+    }
+
+    let (_, output) = run(
+        "triples_labels_short",
+        &[
+            ("square.csv", indexed.as_str()),
+            ("corners.csv", "corner\na\nb\nc\n"),
+        ],
+        &[
+            "1:3",
+            "2",
+            "--input-mode",
+            "triples",
+            "--labels",
+            "corners.csv",
+            "square.csv",
+        ],
+    );
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("out of range of the labels file"),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn invalid_columns_are_fatal_in_lenient_modes() {
+    let files = [("four.csv", "a,b,c,d\n1,2,3,4\n5,6,7\n8,9,10,11\n")];
+    for args in [
+        &["5", "2", "--on-error", "skip", "four.csv"][..],
+        &["1:2", "2", "-w", "5", "--on-error", "skip", "four.csv"],
+    ] {
+        let (_, output) = run("invalid_columns_lenient", &files, args);
+        assert!(!output.status.success(), "{args:?} succeeded");
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(
+            stderr.contains("invalid column numbers 5: header (line 1) has only 4 fields"),
+            "{stderr}"
+        );
+    }
+
+    // Only the short row is skipped
+    let (_, output) = run(
+        "short_row_lenient",
+        &files,
+        &["1:4", "2", "-n", "5", "--on-error", "skip", "four.csv"],
+    );
+    assert_eq!(
+        columns(stdout(&output), 2),
+        ["a,b,c,d", "1,2,3,4", "8,9,10,11"]
+    );
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Skipped 1 of 3 rows"), "{stderr}");
+}
+
+#[test]
+fn batch_of_all_particles_is_exact() {
+    let sample =
+        std::fs::read_to_string(concat!(env!("CARGO_MANIFEST_DIR"), "/sample.csv")).unwrap();
+    let n = sample.lines().count() - 1;
+    let files = [("sample.csv", sample.as_str())];
+    let (_, output) = run("batch_exact", &files, &["2:3", "2", "sample.csv"]);
+    let exact = stdout(&output).to_owned();
+    for k in [n - 1, n + 10] {
+        let (_, output) = run(
+            "batch_exact",
+            &files,
+            &["2:3", "2", "--batch-size", &k.to_string(), "sample.csv"],
+        );
+        assert_eq!(stdout(&output), exact, "--batch-size {k}");
+    }
+    let (_, output) = run(
+        "batch_exact",
+        &files,
+        &[
+            "2:3",
+            "2",
+            "--batch-size",
+            &(n - 2).to_string(),
+            "sample.csv",
+        ],
+    );
+    assert_ne!(stdout(&output), exact);
+}
+
+/// Decompressed content of a file written by csvdimreduce, going by its extension
+fn read_output(path: &Path) -> Vec<u8> {
+    let f = std::fs::File::open(path).unwrap();
+    let mut reader: Box<dyn Read> = match path.extension().and_then(|x| x.to_str()) {
+        Some("gz") => Box::new(flate2::read::GzDecoder::new(f)),
+        Some("zst") => Box::new(zstd::Decoder::new(f).unwrap()),
+        _ => Box::new(f),
+    };
+    let mut data = vec![];
+    reader.read_to_end(&mut data).unwrap();
+    data
+}
+
+#[test]
+fn trajectories() {
+    let sample =
+        std::fs::read_to_string(concat!(env!("CARGO_MANIFEST_DIR"), "/sample.csv")).unwrap();
+    let rows = sample.lines().count() - 1;
+    for name in ["t.csv", "t.csv.gz", "t.npy", "t.npy.gz"] {
+        let (dir, output) = run(
+            "trajectories",
+            &[("sample.csv", &sample)],
+            &[
+                "2:3",
+                "3",
+                "-n",
+                "20",
+                "--save-each-n-iters",
+                "5",
+                "--trajectory",
+                name,
+                "sample.csv",
+            ],
+        );
+        stdout(&output);
+        let data = read_output(&dir.join(name));
+        // Iterations 0, 5, 10, 15 and the final state
+        let frames = 5;
+        if name.contains(".npy") {
+            assert_eq!(&data[..8], b"\x93NUMPY\x01\x00", "{name}");
+            let header_len = u16::from_le_bytes([data[8], data[9]]) as usize;
+            assert_eq!((10 + header_len) % 64, 0, "{name}");
+            let header = std::str::from_utf8(&data[10..10 + header_len]).unwrap();
+            assert!(
+                header.contains(&format!("'shape': ({frames}, {rows}, 3)")),
+                "{name}: {header}"
+            );
+            assert_eq!(
+                data.len(),
+                10 + header_len + frames * rows * 3 * 8,
+                "{name}"
+            );
+        } else {
+            let text = String::from_utf8(data).unwrap();
+            let mut lines = text.lines();
+            assert_eq!(
+                lines.next(),
+                Some("iteration,phase,row_index,coord1,coord2,coord3")
+            );
+            let records: Vec<Vec<&str>> = lines.map(|x| x.split(',').collect()).collect();
+            assert_eq!(records.len(), frames * rows, "{name}");
+            let iterations: Vec<&str> = records.iter().step_by(rows).map(|r| r[0]).collect();
+            assert_eq!(iterations, ["0", "5", "10", "15", "20"], "{name}");
+            let phases: Vec<&str> = records.iter().step_by(rows).map(|r| r[1]).collect();
+            assert_eq!(
+                phases,
+                ["basic", "basic", "basic", "basic", "final"],
+                "{name}"
+            );
+            let indices: Vec<String> = (0..rows).map(|j| j.to_string()).collect();
+            for frame in records.chunks(rows) {
+                let frame_indices: Vec<&str> = frame.iter().map(|r| r[2]).collect();
+                assert_eq!(frame_indices, indices, "{name}");
+            }
+        }
+    }
+}
+
+#[test]
+fn date_and_cyclic_columns() {
+    let layout = |test: &str, data: &str, column_type: &str| {
+        let (_, output) = run(
+            test,
+            &[("data.csv", data)],
+            &[
+                "2",
+                "2",
+                "-n",
+                "20",
+                "--column-type",
+                column_type,
+                "data.csv",
+            ],
+        );
+        // Coordinates and id, without the value that is written differently
+        columns(stdout(&output), 0)
+            .into_iter()
+            .map(|x| x.rsplit_once(',').unwrap().0.to_owned())
+            .collect::<Vec<_>>()
+    };
+    // Dates are seconds since Unix epoch
+    assert_eq!(
+        layout(
+            "date_column",
+            "id,when\n1,2023-01-01\n2,2023-01-02T00:00:00+00:00\n3,2023-01-05 12:00Z\n",
+            "2=date"
+        ),
+        layout(
+            "date_as_number",
+            "id,when\n1,1672531200\n2,1672617600\n3,1672920000\n",
+            "2=number"
+        ),
+    );
+    let hours = "id,hour\n1,23.5\n2,0.5\n3,12\n4,6.25\n";
+    let times = "id,hour\n1,23:30\n2,00:30\n3,12:00\n4,06:15\n";
+    assert_eq!(
+        layout("cyclic_times", times, "2=cyclic:24"),
+        layout("cyclic_hours", hours, "2=cyclic:24"),
+    );
+    assert_ne!(
+        layout("cyclic_hours", hours, "2=cyclic:24"),
+        layout("linear_hours", hours, "2=number"),
+    );
+
+    for (column_type, value, error) in [
+        (
+            "2=date",
+            "2023-02-30",
+            "Invalid ISO 8601 date: `2023-02-30`",
+        ),
+        ("2=cyclic:24", "24:30", "Invalid time of day: `24:30`"),
+    ] {
+        let (_, output) = run(
+            "invalid_date_or_time",
+            &[("data.csv", &format!("id,x\n1,{value}\n"))],
+            &["2", "2", "--column-type", column_type, "data.csv"],
+        );
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.contains(error), "{stderr}");
+        assert!(stderr.contains("column 2 (`x`)"), "{stderr}");
+    }
+}
+
+#[test]
+fn compressed_round_trip() {
+    let sample =
+        std::fs::read_to_string(concat!(env!("CARGO_MANIFEST_DIR"), "/sample.csv")).unwrap();
+    let (dir, output) = run(
+        "compressed_round_trip",
+        &[("sample.csv", &sample)],
+        &["2:3", "2", "-n", "20", "sample.csv"],
+    );
+    let plain = stdout(&output).to_owned();
+    std::fs::write(dir.join("out.csv"), &plain).unwrap();
+    let relaid = stdout(&run_in(&dir, &["1:2", "2", "-n", "20", "out.csv"])).to_owned();
+
+    for name in ["out.csv.gz", "out.csv.zst"] {
+        let output = run_in(&dir, &["2:3", "2", "-n", "20", "-o", name, "sample.csv"]);
+        assert_eq!(stdout(&output), "", "{name}");
+        let data = std::fs::read(dir.join(name)).unwrap();
+        assert_ne!(data, plain.as_bytes(), "{name} is not compressed");
+        assert_eq!(read_output(&dir.join(name)), plain.as_bytes(), "{name}");
+
+        let output = run_in(&dir, &["1:2", "2", "-n", "20", name]);
+        assert_eq!(stdout(&output), relaid, "{name}");
+    }
+}
+
+#[test]
+fn estimate_limits() {
+    let sample =
+        std::fs::read_to_string(concat!(env!("CARGO_MANIFEST_DIR"), "/sample.csv")).unwrap();
+    let files = [("sample.csv", sample.as_str())];
+    let (dir, output) = run(
+        "estimate_limits",
+        &files,
+        &["2:3", "2", "--max-memory", "1K", "sample.csv"],
+    );
+    assert!(!output.status.success());
+    assert!(output.stdout.is_empty());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("exceeds the limit of 1.0 KiB") && stderr.contains("--force"),
+        "{stderr}"
+    );
+
+    let output = run_in(&dir, &["2:3", "2", "--max-runtime", "0s", "sample.csv"]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Estimated running time"), "{stderr}");
+
+    let forced = run_in(
+        &dir,
+        &[
+            "2:3",
+            "2",
+            "--max-memory",
+            "1K",
+            "--max-runtime",
+            "0s",
+            "--force",
+            "sample.csv",
+        ],
+    );
+    let expected = run_in(&dir, &["2:3", "2", "sample.csv"]);
+    assert_eq!(stdout(&forced), stdout(&expected));
+
+    let output = run_in(&dir, &["2:3", "2", "-n", "1", "--debug", "sample.csv"]);
+    assert!(stdout(&output).contains("estimate particles=16 "));
+}
+
+fn stderr(output: &Output) -> String {
+    assert!(!output.status.success());
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+#[test]
+fn error_locations() {
+    let table = "id,x,y,z\n0,1,2,3\n1,abc,2,3\n2,1,2,3\n";
+    let (dir, output) = run(
+        "error_locations",
+        &[("t.csv", table)],
+        &["2:3", "2", "t.csv"],
+    );
+    assert!(stderr(&output)
+        .contains("Failed to parse column 2 (`x`), value `abc`, data row 2 (line 3)"));
+
+    let output = run_in(&dir, &["2:5", "2", "t.csv"]);
+    assert!(stderr(&output).contains(
+        "Field list references invalid column numbers 5: header (line 1) has only 4 fields"
+    ));
+
+    let output = run_in(&dir, &["--no-header", "2,7:8", "2", "t.csv"]);
+    assert!(stderr(&output).contains(
+        "Field list references invalid column numbers 7, 8: data row 1 (line 1) has only 4 fields"
+    ));
+}
+
+#[test]
+fn nan_watchdog() {
+    let sample =
+        std::fs::read_to_string(concat!(env!("CARGO_MANIFEST_DIR"), "/sample.csv")).unwrap();
+    let (_, output) = run(
+        "nan_watchdog",
+        &[("sample.csv", sample.as_str())],
+        &["2:3", "2", "--rate", "1e308", "sample.csv"],
+    );
+    let stderr = stderr(&output);
+    assert!(
+        stderr.contains("Coordinate 1 of particle 0 became NaN after")
+            && stderr.contains("(basic phase)")
+            && stderr.contains("rate=NaN"),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn zero_weights() {
+    let table = "w,x\n0,1\n1,2\n2,3\n";
+    let (dir, output) = run(
+        "zero_weights",
+        &[("t.csv", table)],
+        &["-w", "1", "2", "2", "t.csv"],
+    );
+    assert!(stderr(&output).contains(
+        "Weight should be a finite positive number at column 1 (`w`), value `0`, data row 1 (line 2)"
+    ));
+
+    let output = run_in(&dir, &["-w", "1", "--on-error", "skip", "2", "2", "t.csv"]);
+    let text = stdout(&output);
+    assert_eq!(text.lines().count(), 3);
+    assert!(!text.contains("NaN"));
+
+    let output = run_in(
+        &dir,
+        &["-w", "1", "--bad-weights", "missing", "2", "2", "t.csv"],
+    );
+    let text = stdout(&output);
+    assert_eq!(text.lines().count(), 4);
+    assert!(!text.contains("NaN"));
+}
@@ -0,0 +1,157 @@
+//! Outputs for `sample.csv` and a generated larger input that must not change between platforms or runs.
+//! Run with `UPDATE_GOLDEN=1` to regenerate them after an intended change.
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+fn check(name: &str, args: &[&str]) {
+    let sample = concat!(env!("CARGO_MANIFEST_DIR"), "/sample.csv");
+    check_input(name, Path::new(sample), args);
+}
+
+/// 400 rows scattered by integer arithmetic only, enough for sampling in `--affinity-transform rank`.
+/// Each test writes its own copy, as they run in parallel.
+fn scattered(name: &str) -> PathBuf {
+    let path: PathBuf = [env!("CARGO_TARGET_TMPDIR"), name].iter().collect();
+    let mut text = String::from("id,x,y\n");
+    for i in 0..400 {
+        text += &format!("{i},{},{}\n", i * 37 % 101, i * 53 % 97 + i / 100 * 50);
+    }
+    std::fs::write(&path, text).unwrap();
+    path
+}
+
+fn check_input(name: &str, input: &Path, args: &[&str]) {
+    let output = Command::new(env!("CARGO_BIN_EXE_csvdimreduce"))
+        .args(args)
+        .arg(input)
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{name}: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let actual = String::from_utf8(output.stdout).unwrap();
+    let path: PathBuf = [env!("CARGO_MANIFEST_DIR"), "tests", "golden", name]
+        .iter()
+        .collect();
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, &actual).unwrap();
+        return;
+    }
+    let expected =
+        std::fs::read_to_string(&path).unwrap_or_else(|e| panic!("{}: {e}", path.display()));
+    assert!(
+        actual == expected,
+        "{name} differs from {}:\n{actual}",
+        path.display()
+    );
+}
+
+#[test]
+fn default() {
+    check("default.csv", &["2:3", "2"]);
+}
+
+#[test]
+fn seed() {
+    check("seed.csv", &["2:3", "2", "--random-seed", "42"]);
+}
+
+#[test]
+fn squeeze() {
+    check("squeeze.csv", &["2:3", "3", "-S", "2"]);
+}
+
+#[test]
+fn batch_size() {
+    check("batch_size.csv", &["2:3", "2", "--batch-size", "4"]);
+}
+
+#[test]
+fn landmarks() {
+    check("landmarks.csv", &["2:3", "2", "--landmarks", "8"]);
+}
+
+#[test]
+fn multilevel() {
+    check(
+        "multilevel.csv",
+        &["2:3", "2", "--multilevel", "--coarsest", "4"],
+    );
+}
+
+#[test]
+fn neighbors() {
+    check(
+        "neighbors.csv",
+        &[
+            "2:3",
+            "2",
+            "--neighbors",
+            "3",
+            "--force-law",
+            "gaussian=0.3",
+        ],
+    );
+}
+
+#[test]
+fn f32() {
+    check("f32.csv", &["2:3", "2", "--precision", "f32"]);
+}
+
+#[test]
+fn restarts() {
+    check(
+        "restarts.csv",
+        &["2:3", "2", "--restarts", "3", "--threads", "2"],
+    );
+}
+
+#[test]
+fn all_restarts() {
+    check(
+        "all_restarts.csv",
+        &["2:3", "2", "--restarts", "2", "--all-restarts", "-S", "1"],
+    );
+}
+
+#[test]
+fn stability() {
+    check(
+        "stability.csv",
+        &["2:3", "2", "--stability", "3", "--stability-neighbors", "3"],
+    );
+}
+
+#[test]
+fn rank_sampled() {
+    check_input(
+        "rank_sampled.csv",
+        &scattered("rank_sampled.csv"),
+        &["2:3", "2", "-n", "5", "--affinity-transform", "rank"],
+    );
+}
+
+#[test]
+fn rank_batch_landmarks() {
+    check_input(
+        "rank_batch_landmarks.csv",
+        &scattered("rank_batch_landmarks.csv"),
+        &[
+            "2:3",
+            "2",
+            "-n",
+            "5",
+            "--affinity-transform",
+            "rank",
+            "--batch-size",
+            "5",
+            "--landmarks",
+            "350",
+        ],
+    );
+}